@@ -0,0 +1,399 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::lease::{Clock, SystemClock};
+use crate::model::Strategy;
+use crate::scope::ReusePolicy;
+use crate::socket::SocketConfig;
+
+const DEFAULT_SCHEMA_ROOT: &str = "100.64.0.0/17";
+const DEFAULT_SCHEMA_PREFIX_LENGTH: u8 = 20;
+const ULA_PREFIX_LENGTH: u8 = 64;
+const DEFAULT_STICKY_MAC_IDLE_SECONDS: u32 = 7 * 24 * 60 * 60;
+const DEFAULT_LEASE_SWEEP_SECONDS: u32 = 60;
+const DEFAULT_ADDRESS_COOLDOWN_SECONDS: u32 = 5 * 60;
+pub(crate) const ULA_NETWORK: &str = "ula";
+
+#[derive(Clone)]
+pub(crate) struct DriverConfig {
+    pub requires_mac_address: bool,
+    pub requires_request_replay: bool,
+    // how long a MAC keeps its address after the address was last released, None when off
+    pub sticky_mac_idle: Option<Duration>,
+    // how long an endpoint address is leased for, None when addresses never expire
+    pub lease_ttl: Option<Duration>,
+    pub lease_sweep_interval: Duration,
+    pub address_reuse: ReusePolicy,
+    pub clock: Arc<dyn Clock>,
+    pub socket: Option<SocketConfig>
+}
+
+impl DriverConfig {
+    pub fn from_env() -> Result<DriverConfig, Box<dyn Error>> {
+        Ok(DriverConfig {
+            requires_mac_address: env_flag("REQUIRES_MAC_ADDRESS")?,
+            requires_request_replay: env_flag("REQUIRES_REQUEST_REPLAY")?,
+            sticky_mac_idle: sticky_mac_idle_from_env()?,
+            lease_ttl: env_number("ADDRESS_LEASE_SECONDS", 10)?.map(|s| Duration::from_secs(s as u64)),
+            lease_sweep_interval: Duration::from_secs(env_number("LEASE_SWEEP_SECONDS", 10)?.unwrap_or(DEFAULT_LEASE_SWEEP_SECONDS) as u64),
+            address_reuse: address_reuse_from_env()?,
+            clock: Arc::new(SystemClock),
+            socket: socket_config_from_env()?
+        })
+    }
+}
+
+impl Default for DriverConfig {
+    fn default() -> Self {
+        DriverConfig {
+            requires_mac_address: false,
+            requires_request_replay: false,
+            sticky_mac_idle: None,
+            lease_ttl: None,
+            lease_sweep_interval: Duration::from_secs(DEFAULT_LEASE_SWEEP_SECONDS as u64),
+            address_reuse: ReusePolicy::Immediate,
+            clock: Arc::new(SystemClock),
+            socket: None
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub(crate) struct SchemaLevel {
+    pub prefix_length: u8,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub strategy: Option<Strategy>
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub(crate) struct SchemaRoot {
+    pub network: String,
+    #[serde(default)]
+    pub prefix_length: Option<u8>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub strategy: Option<Strategy>,
+    #[serde(default, rename = "level")]
+    pub levels: Vec<SchemaLevel>
+}
+
+impl SchemaRoot {
+    fn split(network: String, prefix_length: u8) -> SchemaRoot {
+        SchemaRoot {
+            network,
+            prefix_length: Some(prefix_length),
+            tags: Vec::new(),
+            locked: false,
+            strategy: None,
+            levels: Vec::new()
+        }
+    }
+
+    // a bare prefix_length is shorthand for a single level below the root
+    pub fn levels(&self) -> Vec<SchemaLevel> {
+        match (self.levels.is_empty(), self.prefix_length) {
+            (false, _) => self.levels.clone(),
+            (true, Some(prefix_length)) => {
+                vec![SchemaLevel {
+                    prefix_length,
+                    tags: Vec::new(),
+                    locked: false,
+                    strategy: None
+                }]
+            }
+            (true, None) => Vec::new()
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub(crate) struct SchemaConfig {
+    #[serde(default, rename = "root")]
+    pub roots: Vec<SchemaRoot>
+}
+
+#[derive(serde::Deserialize)]
+struct DefaultAddressPool {
+    base: String,
+    size: u8
+}
+
+#[derive(serde::Deserialize)]
+struct DaemonConfig {
+    #[serde(default, rename = "default-address-pools")]
+    default_address_pools: Vec<DefaultAddressPool>
+}
+
+impl SchemaConfig {
+    pub fn from_env() -> Result<SchemaConfig, Box<dyn Error>> {
+        let mut roots: Vec<SchemaRoot> = Vec::new();
+
+        if let Some(path) = env_string("SCHEMA_CONFIG_FILE")? {
+            roots.extend(SchemaConfig::from_toml(&read_file(&path)?)?.roots);
+        }
+
+        if let Some(path) = env_string("DEFAULT_ADDRESS_POOLS_FILE")? {
+            roots.extend(SchemaConfig::from_default_address_pools(&read_file(&path)?)?.roots);
+        }
+
+        if let Some(pools) = env_string("DEFAULT_ADDRESS_POOLS")? {
+            roots.extend(SchemaConfig::from_default_address_pools(&pools)?.roots);
+        }
+
+        let mut config = match roots.is_empty() {
+            true => SchemaConfig::default(),
+            false => SchemaConfig { roots }
+        };
+
+        if env_flag("GENERATE_ULA")? && !config.roots.iter().any(|r| r.network == ULA_NETWORK) {
+            config.roots.push(SchemaRoot::split(ULA_NETWORK.to_string(), ULA_PREFIX_LENGTH));
+        }
+
+        Ok(config)
+    }
+
+    // accepts either a whole daemon.json or just its default-address-pools array
+    pub fn from_default_address_pools(contents: &str) -> Result<SchemaConfig, Box<dyn Error>> {
+        let pools: Vec<DefaultAddressPool> = match serde_json::from_str::<DaemonConfig>(contents) {
+            Ok(daemon) => daemon.default_address_pools,
+            Err(_) => serde_json::from_str(contents)?
+        };
+
+        match pools.is_empty() {
+            true => Err("no default-address-pools declared".into()),
+            false => {
+                Ok(SchemaConfig {
+                    roots: pools
+                        .into_iter()
+                        .map(|p| SchemaRoot::split(p.base, p.size))
+                        .collect()
+                })
+            }
+        }
+    }
+
+    pub fn from_toml(contents: &str) -> Result<SchemaConfig, Box<dyn Error>> {
+        let config: SchemaConfig = toml::from_str(contents)?;
+
+        match config.roots.is_empty() {
+            true => Err("schema config must declare at least one [[root]]".into()),
+            false => Ok(config)
+        }
+    }
+}
+
+impl Default for SchemaConfig {
+    fn default() -> Self {
+        SchemaConfig {
+            roots: vec![SchemaRoot::split(DEFAULT_SCHEMA_ROOT.to_string(), DEFAULT_SCHEMA_PREFIX_LENGTH)]
+        }
+    }
+}
+
+fn read_file(path: &str) -> Result<String, Box<dyn Error>> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(e) => Err(format!("failed to read {}: {}", path, e).into())
+    }
+}
+
+fn socket_config_from_env() -> Result<Option<SocketConfig>, Box<dyn Error>> {
+    match env_string("PLUGIN_SOCKET")? {
+        Some(path) => {
+            Ok(Some(SocketConfig {
+                path: PathBuf::from(path),
+                owner: env_number("PLUGIN_SOCKET_OWNER", 10)?,
+                group: env_number("PLUGIN_SOCKET_GROUP", 10)?,
+                mode: env_number("PLUGIN_SOCKET_MODE", 8)?,
+                spec_file: env_string("PLUGIN_SPEC_FILE")?.map(PathBuf::from)
+            }))
+        }
+        None => Ok(None)
+    }
+}
+
+fn sticky_mac_idle_from_env() -> Result<Option<Duration>, Box<dyn Error>> {
+    Ok(sticky_mac_idle(env_flag("STICKY_MAC_ADDRESSES")?, env_number("STICKY_MAC_IDLE_SECONDS", 10)?))
+}
+
+fn sticky_mac_idle(enabled: bool, idle_seconds: Option<u32>) -> Option<Duration> {
+    match enabled {
+        true => Some(Duration::from_secs(idle_seconds.unwrap_or(DEFAULT_STICKY_MAC_IDLE_SECONDS) as u64)),
+        false => None
+    }
+}
+
+fn address_reuse_from_env() -> Result<ReusePolicy, Box<dyn Error>> {
+    address_reuse(env_string("ADDRESS_REUSE")?.as_deref(), env_number("ADDRESS_COOLDOWN_SECONDS", 10)?)
+}
+
+fn address_reuse(policy: Option<&str>, cooldown_seconds: Option<u32>) -> Result<ReusePolicy, Box<dyn Error>> {
+    match policy {
+        None | Some("immediate") => Ok(ReusePolicy::Immediate),
+        Some("lru") => Ok(ReusePolicy::LeastRecentlyUsed),
+        Some("cooldown") => {
+            let seconds = cooldown_seconds.unwrap_or(DEFAULT_ADDRESS_COOLDOWN_SECONDS);
+            Ok(ReusePolicy::Cooldown(Duration::from_secs(seconds as u64)))
+        }
+        Some(other) => Err(format!("ADDRESS_REUSE must be immediate, cooldown or lru, got {}", other).into())
+    }
+}
+
+fn env_string(name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    match std::env::var(name) {
+        Ok(v) if v.is_empty() => Ok(None),
+        Ok(v) => Ok(Some(v)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(Box::new(e))
+    }
+}
+
+fn env_number(name: &str, radix: u32) -> Result<Option<u32>, Box<dyn Error>> {
+    parse_number(name, env_string(name)?.as_deref(), radix)
+}
+
+fn parse_number(name: &str, value: Option<&str>, radix: u32) -> Result<Option<u32>, Box<dyn Error>> {
+    match value {
+        Some(v) => {
+            match u32::from_str_radix(v.trim_start_matches("0o"), radix) {
+                Ok(n) => Ok(Some(n)),
+                Err(_) => Err(format!("{} must be a base {} number, got {}", name, radix, v).into())
+            }
+        }
+        None => Ok(None)
+    }
+}
+
+fn env_flag(name: &str) -> Result<bool, Box<dyn Error>> {
+    match std::env::var(name) {
+        Ok(v) => parse_flag(name, Some(&v)),
+        Err(std::env::VarError::NotPresent) => parse_flag(name, None),
+        Err(e) => Err(Box::new(e))
+    }
+}
+
+fn parse_flag(name: &str, value: Option<&str>) -> Result<bool, Box<dyn Error>> {
+    match value.map(|v| v.to_lowercase()).as_deref() {
+        None | Some("") | Some("0") | Some("false") | Some("no") => Ok(false),
+        Some("1") | Some("true") | Some("yes") => Ok(true),
+        Some(_) => Err(format!("{} must be a boolean, got {}", name, value.unwrap_or_default()).into())
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use crate::config::*;
+
+    #[test]
+    fn flags_parse_booleans() {
+        assert_eq!(parse_flag("FLAG", Some("True")).unwrap(), true);
+        assert_eq!(parse_flag("FLAG", Some("0")).unwrap(), false);
+        assert_eq!(parse_flag("FLAG", None).unwrap(), false);
+        assert_eq!(parse_flag("FLAG", Some("maybe")).is_err(), true);
+    }
+
+    #[test]
+    fn schema_config_reads_roots() {
+        let config = SchemaConfig::from_toml(r#"
+            [[root]]
+            network = "10.10.0.0/16"
+            prefix_length = 24
+            tags = ["team-a"]
+
+            [[root]]
+            network = "10.20.0.0/16"
+            prefix_length = 26
+            locked = true
+        "#).unwrap();
+
+        assert_eq!(config.roots.len(), 2);
+        assert_eq!(config.roots[0].tags, vec!["team-a"]);
+        assert_eq!(config.roots[0].locked, false);
+        assert_eq!(config.roots[1].prefix_length, Some(26));
+        assert_eq!(config.roots[1].locked, true);
+        assert_eq!(SchemaConfig::from_toml("").is_err(), true);
+        assert_eq!(SchemaConfig::default().roots[0].network, "100.64.0.0/17");
+    }
+
+    #[test]
+    fn schema_config_reads_levels() {
+        let config = SchemaConfig::from_toml(r#"
+            [[root]]
+            network = "10.0.0.0/12"
+
+            [[root.level]]
+            prefix_length = 16
+
+            [[root.level]]
+            prefix_length = 20
+            locked = true
+
+            [[root.level]]
+            prefix_length = 24
+            tags = ["edge"]
+            strategy = "hashed"
+
+            [[root]]
+            network = "10.32.0.0/16"
+            prefix_length = 24
+            strategy = "random"
+        "#).unwrap();
+
+        let levels = config.roots[0].levels();
+        assert_eq!(levels.iter().map(|l| l.prefix_length).collect::<Vec<u8>>(), vec![16, 20, 24]);
+        assert_eq!(levels[1].locked, true);
+        assert_eq!(levels[2].tags, vec!["edge"]);
+        assert_eq!(levels[2].strategy, Some(Strategy::Hashed));
+        assert_eq!(levels[0].strategy, None);
+        assert_eq!(config.roots[1].levels().len(), 1);
+        assert_eq!(config.roots[1].strategy, Some(Strategy::Random));
+        assert_eq!(SchemaConfig::from_toml("[[root]]\nnetwork = \"10.0.0.0/16\"\nstrategy = \"best-fit\"").is_err(), true);
+    }
+
+    #[test]
+    fn schema_config_reads_default_address_pools() {
+        let inline = SchemaConfig::from_default_address_pools(
+            r#"[{"base":"172.80.0.0/16","size":24},{"base":"172.90.0.0/16","size":24}]"#).unwrap();
+        let daemon = SchemaConfig::from_default_address_pools(
+            r#"{"log-driver":"json-file","default-address-pools":[{"base":"172.80.0.0/16","size":24}]}"#).unwrap();
+
+        assert_eq!(inline.roots.len(), 2);
+        assert_eq!(inline.roots[1].network, "172.90.0.0/16");
+        assert_eq!(daemon.roots[0].prefix_length, Some(24));
+        assert_eq!(SchemaConfig::from_default_address_pools(r#"{"log-driver":"json-file"}"#).is_err(), true);
+        assert_eq!(SchemaConfig::from_default_address_pools("[{\"base\":\"172.80.0.0/16\"}]").is_err(), true);
+    }
+
+    #[test]
+    fn sticky_mac_addresses_are_opt_in() {
+        assert_eq!(sticky_mac_idle(false, Some(3600)), None);
+        assert_eq!(sticky_mac_idle(true, None), Some(Duration::from_secs(604800)));
+        assert_eq!(sticky_mac_idle(true, Some(3600)), Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn address_reuse_policies() {
+        assert_eq!(address_reuse(None, None).unwrap(), ReusePolicy::Immediate);
+        assert_eq!(address_reuse(Some("immediate"), Some(60)).unwrap(), ReusePolicy::Immediate);
+        assert_eq!(address_reuse(Some("cooldown"), None).unwrap(), ReusePolicy::Cooldown(Duration::from_secs(300)));
+        assert_eq!(address_reuse(Some("cooldown"), Some(60)).unwrap(), ReusePolicy::Cooldown(Duration::from_secs(60)));
+        assert_eq!(address_reuse(Some("lru"), None).unwrap(), ReusePolicy::LeastRecentlyUsed);
+        assert_eq!(address_reuse(Some("random"), None).is_err(), true);
+    }
+
+    #[test]
+    fn numbers_parse_octal_modes() {
+        assert_eq!(parse_number("MODE", Some("0660"), 8).unwrap(), Some(0o660));
+        assert_eq!(parse_number("MODE", Some("0o660"), 8).unwrap(), Some(0o660));
+        assert_eq!(parse_number("OWNER", Some("root"), 10).is_err(), true);
+        assert_eq!(parse_number("OWNER", None, 10).unwrap(), None);
+    }
+}
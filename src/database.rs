@@ -1,13 +1,269 @@
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
+use std::sync::{Arc, Mutex, MutexGuard};
+use cidr::IpCidr;
+use log::info;
+use crate::config::SchemaConfig;
+use crate::error::*;
+use crate::model::*;
+use crate::schema::*;
+use crate::scope::*;
+use crate::storage::{Store, Transaction};
+use crate::util;
 
-pub(crate) fn initialize_scope_database() -> Result<bool, Box<dyn Error>> { 
-    todo!()   
+// seeds an empty schema store, returns whether it had to
+pub(crate) fn initialize_schema_database(db: &mut dyn Store, config: &SchemaConfig) -> IpamResult<bool> {
+    let seeded = !Schema::is_db_initialized(db)?;
+
+    Schema::initialize_with(db, config)?;
+    Ok(seeded)
+}
+
+// derives scopes from the schema when the scope store is empty, returns whether it had to
+pub(crate) fn initialize_scope_database(db: &mut dyn Store, schema_db: &mut dyn Store) -> IpamResult<bool> {
+    let created = !Scope::is_db_initialized(db)?;
+
+    Scope::initialize_db(db)?;
+
+    if created {
+        util::create_initial_scopes(db, schema_db)?;
+    }
+
+    Ok(created)
+}
+
+// every schema node needs a scope, and every scope has to sit inside the schema. Both stores are
+// read once and indexed, since a ULA root alone can hold 65536 records
+pub(crate) fn verify_databases(scope_db: &dyn Store, schema_db: &dyn Store) -> IpamResult<()> {
+    let scopes: BTreeMap<(Family, u128), Selection<Scope>> = Scope::records(scope_db)?
+        .into_iter()
+        .map(|s| ((s.actual.family, s.actual.id), s))
+        .collect();
+    let schemas: BTreeMap<(Family, u128), Selection<Schema>> = Schema::records(schema_db)?
+        .into_iter()
+        .map(|s| ((s.actual.family, s.actual.pool), s))
+        .collect();
+
+    for schema in schemas.values() {
+        let scope = scopes.get(&(schema.actual.family, schema.actual.pool));
+
+        for description in &schema.actual.descriptions {
+            let pool = util::id_to_ip_cidr(schema.actual.family, schema.actual.pool, description.prefix_length)?;
+
+            match scope {
+                Some(scope) if scope.actual.descriptions.iter().any(|d| d.prefix_length == description.prefix_length) => (),
+                _ => {
+                    return Err(IpamError::ScopeInit(format!("schema pool {:#} has no scope", pool)))
+                }
+            }
+        }
+    }
+
+    for scope in scopes.values() {
+        for description in &scope.actual.descriptions {
+            let pool = util::id_to_ip_cidr(scope.actual.family, scope.actual.id, description.prefix_length)?;
+
+            if !within_schema(&pool, scope, &scopes, &schemas)? {
+                return Err(IpamError::ScopeInit(format!("scope {:#} is not within the schema", pool)));
+            }
+        }
+    }
+
+    Ok(())
 }
 
-pub(crate) fn initialize_schema_database() -> Result<bool, Box<dyn Error>> { 
-    todo!()   
+// walks up from the scope's own record through parent ids until a schema node covers the pool
+fn within_schema(pool: &IpCidr, scope: &Selection<Scope>, scopes: &BTreeMap<(Family, u128), Selection<Scope>>, schemas: &BTreeMap<(Family, u128), Selection<Schema>>) -> IpamResult<bool> {
+    let family = scope.actual.family;
+    let mut visited: HashSet<u128> = HashSet::new();
+    let mut next = Some(scope.actual.id);
+
+    while let Some(id) = next.filter(|id| visited.insert(*id)) {
+        next = match schemas.get(&(family, id)) {
+            Some(schema) => {
+                for description in schema.actual.descriptions.iter().filter(|d| d.prefix_length <= pool.network_length()) {
+                    if util::id_to_ip_cidr(family, id, description.prefix_length)?.contains(&pool.first_address()) {
+                        return Ok(true);
+                    }
+                }
+
+                schema.actual.parent
+            }
+            None => scopes.get(&(family, id)).and_then(|s| s.actual.parent)
+        };
+    }
+
+    Ok(false)
 }
 
-pub(crate) fn initialize_databases() -> Result<(), Box<dyn Error>> {
-    todo!()
-}
\ No newline at end of file
+// opened once at startup and shared by every handler; clones share the same stores. A
+// transaction holds the scope store for its whole read-modify-write, which serializes allocations;
+// the scope store is always locked before the schema store so two callers can't deadlock on them
+#[derive(Clone)]
+pub(crate) struct Databases {
+    scope: Arc<Mutex<Box<dyn Store>>>,
+    schema: Arc<Mutex<Box<dyn Store>>>
+}
+
+impl Databases {
+    pub fn new(scope: Box<dyn Store>, schema: Box<dyn Store>) -> Databases {
+        Databases {
+            scope: Arc::new(Mutex::new(scope)),
+            schema: Arc::new(Mutex::new(schema))
+        }
+    }
+
+    pub fn open() -> IpamResult<Databases> {
+        Ok(Databases::new(Scope::dao()?, Schema::dao()?))
+    }
+
+    fn guard<'a>(store: &'a Mutex<Box<dyn Store>>, name: &str) -> IpamResult<MutexGuard<'a, Box<dyn Store>>> {
+        store.lock().map_err(|_| IpamError::Storage(format!("{} store lock is poisoned", name)))
+    }
+
+    // runs f in a transaction over both stores, committing only when it succeeds
+    pub fn transaction<T, F>(&self, f: F) -> IpamResult<T>
+    where F: FnOnce(&mut dyn Store, &mut dyn Store) -> IpamResult<T> {
+        let mut scope = Databases::guard(&self.scope, "scope")?;
+        let mut schema = Databases::guard(&self.schema, "schema")?;
+        let mut tx = Transaction::begin(scope.as_mut(), schema.as_mut())?;
+        let (scope, schema) = tx.stores();
+
+        let ret = f(scope, schema)?;
+        tx.commit()?;
+        Ok(ret)
+    }
+
+    // for handlers that only read: every read takes a shared lock of its own instead of the
+    // exclusive one a transaction holds, and the guards keep this driver's writes out meanwhile
+    pub fn read<T, F>(&self, f: F) -> IpamResult<T>
+    where F: FnOnce(&dyn Store, &dyn Store) -> IpamResult<T> {
+        let scope = Databases::guard(&self.scope, "scope")?;
+        let schema = Databases::guard(&self.schema, "schema")?;
+
+        f(scope.as_ref(), schema.as_ref())
+    }
+}
+
+// tags an error with the bootstrap stage it came from, unless it already names one
+fn in_stage(e: IpamError, stage: fn(String) -> IpamError) -> IpamError {
+    match e {
+        IpamError::SchemaInit(_) | IpamError::ScopeInit(_) => e,
+        other => stage(other.to_string())
+    }
+}
+
+// the whole bootstrap is one transaction, so a failed stage leaves both stores as they were
+pub(crate) fn initialize_databases(databases: &Databases, config: &SchemaConfig) -> Result<(), Box<dyn Error>> {
+    let (seeded, created) = databases.transaction(|scope, schema| {
+        let seeded = initialize_schema_database(schema, config).map_err(|e| in_stage(e, IpamError::SchemaInit))?;
+        let created = initialize_scope_database(scope, schema).map_err(|e| in_stage(e, IpamError::ScopeInit))?;
+
+        verify_databases(scope, schema)?;
+        Ok((seeded, created))
+    })?;
+
+    if seeded {
+        info!("seeded schema database");
+    }
+
+    if created {
+        info!("created initial scopes");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod database_tests {
+    use cidr::IpCidr;
+    use std::str::FromStr;
+    use crate::database::*;
+    use crate::interpolate::{factory as faktory, ProtoScope};
+    use crate::storage::MemoryStore;
+
+    #[test]
+    fn bootstrap_is_idempotent() {
+        let mut schema_db = MemoryStore::new();
+        let mut scope_db = MemoryStore::new();
+
+        assert_eq!(initialize_schema_database(&mut schema_db, &SchemaConfig::default()).unwrap(), true);
+        assert_eq!(initialize_scope_database(&mut scope_db, &mut schema_db).unwrap(), true);
+        verify_databases(&scope_db, &schema_db).unwrap();
+
+        let pool = Scope::allocate_tagged_pool(&Vec::new(), false, &mut scope_db).unwrap().pool_id().unwrap();
+        Scope::claim_pool(IpCidr::from_str("100.64.16.128/25").unwrap(), None, &mut scope_db, &schema_db).unwrap();
+        verify_databases(&scope_db, &schema_db).unwrap();
+
+        assert_eq!(initialize_schema_database(&mut schema_db, &SchemaConfig::default()).unwrap(), false);
+        assert_eq!(initialize_scope_database(&mut scope_db, &mut schema_db).unwrap(), false);
+        verify_databases(&scope_db, &schema_db).unwrap();
+        assert_eq!(Scope::select(&pool, &mut scope_db).unwrap().selected_description().unwrap().allocated, true);
+    }
+
+    #[test]
+    fn bootstrap_seeds_from_the_given_config() {
+        let databases = Databases::new(Box::new(MemoryStore::new()), Box::new(MemoryStore::new()));
+        let config = SchemaConfig::from_toml("[[root]]\nnetwork = \"10.10.0.0/16\"\nprefix_length = 24").unwrap();
+        initialize_databases(&databases, &config).unwrap();
+
+        let pool_id = databases.transaction(|scope, _| Scope::allocate_pool(Vec::new(), false, scope)?.pool_id()).unwrap();
+        assert_eq!(pool_id, "10.10.0.0/24");
+        assert_eq!(databases.read(|scope, schema| Ok(Scope::records(scope)?.len() == Schema::records(schema)?.len())).unwrap(), true);
+    }
+
+    #[test]
+    fn parallel_allocations_are_unique() {
+        let databases = std::sync::Arc::new(Databases::new(Box::new(MemoryStore::new()), Box::new(MemoryStore::new())));
+        initialize_databases(&databases, &SchemaConfig::default()).unwrap();
+        let pool_id = databases.transaction(|scope, _| Scope::allocate_pool(Vec::new(), false, scope)?.pool_id()).unwrap();
+
+        let workers: Vec<_> = (0..16)
+            .map(|_| {
+                let databases = databases.clone();
+                let pool_id = pool_id.clone();
+
+                std::thread::spawn(move || {
+                    (0..25)
+                        .map(|_| databases.transaction(|scope, _| Scope::allocate_address(pool_id.clone(), None, scope)).unwrap())
+                        .map(|address| address.address().to_string())
+                        .collect::<Vec<String>>()
+                })
+            })
+            .collect();
+
+        let allocated: Vec<String> = workers.into_iter().flat_map(|w| w.join().unwrap()).collect();
+        let unique: std::collections::HashSet<&String> = allocated.iter().collect();
+
+        assert_eq!(allocated.len(), 400);
+        assert_eq!(unique.len(), 400);
+        assert_eq!(
+            databases.transaction(|scope, _| Ok(Scope::select(&pool_id, scope)?.selected_description().unwrap().addresses.len())).unwrap(),
+            400);
+    }
+
+    #[test]
+    fn mismatched_stores_are_reported() {
+        let mut schema_db = MemoryStore::new();
+        let mut scope_db = MemoryStore::new();
+        initialize_schema_database(&mut schema_db, &SchemaConfig::default()).unwrap();
+
+        assert_eq!(
+            verify_databases(&scope_db, &schema_db).err(),
+            Some(IpamError::ScopeInit("schema pool 100.64.0.0/17 has no scope".to_string())));
+
+        initialize_scope_database(&mut scope_db, &mut schema_db).unwrap();
+        let mut stray = Scope::new_from_proto_scope(
+            ProtoScope::new_type_backed_proto_scope(IpCidr::from_str("10.0.0.0/24").unwrap()).unwrap(),
+            None).unwrap();
+        stray.actual.descriptions.push(ScopeDescription {
+            allocated: true,
+            ..ScopeDescription::new(24)
+        });
+        Scope::save(&mut stray, &mut scope_db).unwrap();
+
+        assert_eq!(
+            verify_databases(&scope_db, &schema_db).err(),
+            Some(IpamError::ScopeInit("scope 10.0.0.0/24 is not within the schema".to_string())));
+    }
+}
@@ -1,42 +1,134 @@
 use std::error::Error;
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use rocket::http::Status;
 
-#[derive(Debug, Clone)]
-pub(crate) struct DBSaveError;
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum IpamError {
+    PoolExhausted(String),
+    NoSuchPool(String),
+    PoolOverlap(String),
+    AddressInUse(String),
+    InvalidCidr(String),
+    InvalidRequest(String),
+    SchemaLocked(String),
+    StaleRecord(String),
+    SchemaInit(String),
+    ScopeInit(String),
+    DatabaseInUse(String, Option<u32>),
+    Storage(String)
+}
 
-#[derive(Debug, Clone)]
-pub(crate) struct SchemaInitError;
+pub(crate) type IpamResult<T> = Result<T, IpamError>;
 
-#[derive(Debug, Clone)]
-pub(crate) struct ScopeInitError;
+impl IpamError {
+    pub fn status(&self) -> Status {
+        match self {
+            IpamError::PoolExhausted(_) => Status::ServiceUnavailable,
+            IpamError::NoSuchPool(_) => Status::NotFound,
+            IpamError::PoolOverlap(_) => Status::Conflict,
+            IpamError::AddressInUse(_) => Status::Conflict,
+            IpamError::InvalidCidr(_) => Status::BadRequest,
+            IpamError::InvalidRequest(_) => Status::BadRequest,
+            IpamError::SchemaLocked(_) => Status::new(423, "Locked"),
+            IpamError::StaleRecord(_) => Status::Conflict,
+            IpamError::SchemaInit(_) => Status::InternalServerError,
+            IpamError::ScopeInit(_) => Status::InternalServerError,
+            IpamError::DatabaseInUse(_, _) => Status::ServiceUnavailable,
+            IpamError::Storage(_) => Status::InternalServerError
+        }
+    }
+}
 
-impl Display for DBSaveError {
+impl Display for IpamError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.write_str("Error saving record")
+        match self {
+            IpamError::PoolExhausted(what) => write!(f, "no free addresses left in {}", what),
+            IpamError::NoSuchPool(pool) => write!(f, "pool {} does not exist or is not allocated", pool),
+            IpamError::PoolOverlap(pool) => write!(f, "pool {} overlaps a pool that is already allocated", pool),
+            IpamError::AddressInUse(address) => write!(f, "address {} is already in use", address),
+            IpamError::InvalidCidr(cidr) => write!(f, "invalid address or CIDR: {}", cidr),
+            IpamError::InvalidRequest(reason) => write!(f, "invalid request: {}", reason),
+            IpamError::SchemaLocked(what) => write!(f, "{} is locked, no new allocations are allowed from it", what),
+            IpamError::StaleRecord(pool) => write!(f, "{} was changed by another request, retry", pool),
+            IpamError::SchemaInit(reason) => write!(f, "failed to initialize schema database: {}", reason),
+            IpamError::ScopeInit(reason) => write!(f, "failed to initialize scope database: {}", reason),
+            IpamError::DatabaseInUse(path, Some(pid)) => write!(f, "database {} in use by PID {}", path, pid),
+            IpamError::DatabaseInUse(path, None) => write!(f, "database {} in use by another process", path),
+            IpamError::Storage(reason) => write!(f, "storage error: {}", reason)
+        }
     }
 }
 
-impl Error for DBSaveError {
+impl Error for IpamError {
+
+}
+
+impl From<unqlite::Error> for IpamError {
+    fn from(e: unqlite::Error) -> Self {
+        IpamError::Storage(e.to_string())
+    }
+}
 
+impl From<std::io::Error> for IpamError {
+    fn from(e: std::io::Error) -> Self {
+        IpamError::Storage(e.to_string())
+    }
 }
 
-impl Display for SchemaInitError {
-    fn fmt(&self, _f: &mut Formatter<'_>) -> fmt::Result {
-        todo!()
+impl From<serde_json::Error> for IpamError {
+    fn from(e: serde_json::Error) -> Self {
+        IpamError::Storage(format!("corrupt record: {}", e))
     }
 }
 
-impl Error for SchemaInitError {
+impl From<std::net::AddrParseError> for IpamError {
+    fn from(e: std::net::AddrParseError) -> Self {
+        IpamError::InvalidCidr(e.to_string())
+    }
+}
 
+impl From<cidr::errors::NetworkParseError> for IpamError {
+    fn from(e: cidr::errors::NetworkParseError) -> Self {
+        IpamError::InvalidCidr(e.to_string())
+    }
 }
 
-impl Display for ScopeInitError {
-    fn fmt(&self, _f: &mut Formatter<'_>) -> fmt::Result {
-        todo!()
+impl From<cidr::errors::NetworkLengthTooLongError> for IpamError {
+    fn from(e: cidr::errors::NetworkLengthTooLongError) -> Self {
+        IpamError::InvalidCidr(e.to_string())
     }
 }
 
-impl Error for ScopeInitError {
-    
-}
\ No newline at end of file
+#[cfg(test)]
+mod error_tests {
+    use crate::error::*;
+
+    #[test]
+    fn statuses() {
+        assert_eq!(IpamError::NoSuchPool("100.64.0.0/20".to_string()).status(), Status::NotFound);
+        assert_eq!(IpamError::AddressInUse("100.64.0.1".to_string()).status(), Status::Conflict);
+        assert_eq!(IpamError::SchemaLocked("100.64.0.0/17".to_string()).status().code, 423);
+        assert_eq!(IpamError::Storage("disk full".to_string()).status(), Status::InternalServerError);
+    }
+
+    #[test]
+    fn database_in_use_names_the_holder() {
+        assert_eq!(
+            IpamError::DatabaseInUse("/var/lib/ipam/scope.db".to_string(), Some(4242)).to_string(),
+            "database /var/lib/ipam/scope.db in use by PID 4242");
+        assert_eq!(
+            IpamError::DatabaseInUse("/var/lib/ipam/scope.db".to_string(), None).to_string(),
+            "database /var/lib/ipam/scope.db in use by another process");
+    }
+
+    #[test]
+    fn parse_errors_are_invalid_cidr() {
+        let e: IpamError = "100.64.0.1/20".parse::<cidr::IpCidr>().unwrap_err().into();
+
+        match e {
+            IpamError::InvalidCidr(_) => (),
+            _ => panic!("expected InvalidCidr, got {:?}", e)
+        }
+    }
+}
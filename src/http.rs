@@ -1,42 +1,809 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::time::SystemTime;
+use cidr::IpCidr;
 use rocket::*;
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::json::Json;
+use crate::config::DriverConfig;
+use crate::database::Databases;
+use crate::error::*;
+use crate::model::*;
+use crate::schema::Schema;
+use crate::scope::*;
+use crate::socket;
+use crate::storage::Store;
+use crate::util;
 
-struct IpamConf {
-    PreferredPool: String,
-    SubPool: String,
-    Options: Vec<String>,
-    Gateway: String,
-    AuxAddresses: Vec<String>
+const LOCAL_DEFAULT_ADDRESS_SPACE: &str = "LocalDefault";
+const GLOBAL_DEFAULT_ADDRESS_SPACE: &str = "GlobalDefault";
+const IPAM_DRIVER: &str = "IpamDriver";
+const REQUEST_ADDRESS_TYPE: &str = "RequestAddressType";
+const GATEWAY_ADDRESS_TYPE: &str = "com.docker.network.gateway";
+const MAC_ADDRESS_OPTION: &str = "com.docker.network.endpoint.macaddress";
+const TAGS_OPTION: &str = "tags";
+
+type IpamResponse<T> = Result<Json<T>, IpamError>;
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct IpamConf {
+    #[serde(default)]
+    address_space: String,
+    #[serde(default, rename = "Pool")]
+    preferred_pool: String,
+    #[serde(default)]
+    sub_pool: String,
+    #[serde(default)]
+    options: Option<HashMap<String, String>>,
+    #[serde(default, rename = "V6")]
+    v6: bool
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct ActivateResponse {
+    implements: Vec<String>
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct CapabilitiesResponse {
+    #[serde(rename = "RequiresMACAddress")]
+    requires_mac_address: bool,
+    requires_request_replay: bool
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct AddressSpacesResponse {
+    local_default_address_space: String,
+    global_default_address_space: String
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct RequestPoolResponse {
+    #[serde(rename = "PoolID")]
+    pool_id: String,
+    pool: String,
+    data: HashMap<String, String>
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct ReleasePoolRequest {
+    #[serde(rename = "PoolID")]
+    pool_id: String
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct RequestAddressRequest {
+    #[serde(rename = "PoolID")]
+    pool_id: String,
+    #[serde(default)]
+    address: String,
+    #[serde(default)]
+    options: Option<HashMap<String, String>>
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct RequestAddressResponse {
+    address: String,
+    data: HashMap<String, String>
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct ReleaseAddressRequest {
+    #[serde(rename = "PoolID")]
+    pool_id: String,
+    address: String
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct LockRequest {
+    pool: String,
+    #[serde(default)]
+    by: String,
+    #[serde(default)]
+    reason: String
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct UnlockRequest {
+    pool: String
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct RenewLeaseRequest {
+    #[serde(rename = "PoolID")]
+    pool_id: String,
+    address: String
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct RenewLeaseResponse {
+    address: String,
+    expires: u64
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct LockStatus {
+    pool: String,
+    by: Option<String>,
+    reason: Option<String>,
+    since: Option<u64>
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct LocksResponse {
+    locks: Vec<LockStatus>
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct EmptyResponse {}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct ErrorResponse {
+    err: String
+}
+
+impl<'r> Responder<'r> for IpamError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let status = self.status();
+        let body = Json(ErrorResponse {
+            err: self.to_string()
+        });
+
+        Response::build_from(body.respond_to(request)?)
+            .status(status)
+            .ok()
+    }
+}
+
+// locks seeded from the schema config carry no metadata
+fn lock_status(pool: &IpCidr, lock: &Option<Lock>) -> LockStatus {
+    LockStatus {
+        pool: format!("{:#}", pool),
+        by: lock.as_ref().map(|l| l.by.clone()),
+        reason: lock.as_ref().map(|l| l.reason.clone()),
+        since: lock.as_ref()
+            .and_then(|l| l.since.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+    }
+}
+
+fn requested_tags(options: &Option<HashMap<String, String>>) -> Vec<String> {
+    match options.as_ref().and_then(|o| o.get(TAGS_OPTION)) {
+        Some(tags) => {
+            tags.split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect()
+        }
+        None => Vec::new()
+    }
+}
+
+// checked up front, since the option is kept with the pool and read again for every address
+fn requested_strategy(options: &Option<HashMap<String, String>>) -> IpamResult<Option<Strategy>> {
+    match options.as_ref().and_then(|o| o.get(STRATEGY_OPTION)) {
+        Some(strategy) => Ok(Some(Strategy::parse(strategy)?)),
+        None => Ok(None)
+    }
+}
+
+// sticky and hashed placement both key on the MAC, which Docker only sends when asked to. The
+// schema is only seeded at startup, so rocket() works this out once
+fn mac_address_required(config: &DriverConfig, schema: &dyn Store) -> IpamResult<bool> {
+    Ok(config.requires_mac_address
+        || config.sticky_mac_idle.is_some()
+        || Schema::records(schema)?.iter().any(|s| s.actual.descriptions.iter().any(|d| d.strategy == Some(Strategy::Hashed))))
+}
+
+#[post("/Plugin.Activate")]
+fn activate() -> Json<ActivateResponse> {
+    Json(ActivateResponse {
+        implements: vec![IPAM_DRIVER.to_string()]
+    })
+}
+
+#[post("/IpamDriver.GetCapabilities")]
+fn get_capabilities(config: State<DriverConfig>) -> Json<CapabilitiesResponse> {
+    Json(CapabilitiesResponse {
+        requires_mac_address: config.requires_mac_address,
+        requires_request_replay: config.requires_request_replay
+    })
+}
+
+#[post("/IpamDriver.GetDefaultAddressSpaces")]
+fn get_default_address_spaces() -> Json<AddressSpacesResponse> {
+    Json(AddressSpacesResponse {
+        local_default_address_space: LOCAL_DEFAULT_ADDRESS_SPACE.to_string(),
+        global_default_address_space: GLOBAL_DEFAULT_ADDRESS_SPACE.to_string()
+    })
+}
+
+#[post("/IpamDriver.RequestPool", data = "<conf>")]
+fn request_pool(conf: Json<IpamConf>, config: State<DriverConfig>, databases: State<Databases>) -> IpamResponse<RequestPoolResponse> {
+    let conf = conf.into_inner();
+
+    if conf.preferred_pool.is_empty() && !conf.sub_pool.is_empty() {
+        return Err(IpamError::InvalidRequest("SubPool requires Pool to be set".to_string()));
+    }
+
+    let strategy = requested_strategy(&conf.options)?;
+    let key = conf.options.as_ref().and_then(|o| o.get(STRATEGY_KEY_OPTION)).cloned();
+    let now = config.clock.now();
+
+    let request = RequestIdentity {
+        options: conf.options.clone().unwrap_or_default().into_iter().collect()
+    };
+
+    // libnetwork reserves --gateway and --aux-address with RequestAddress once it has the pool
+    if strategy == Some(Strategy::Hashed) && !config.requires_mac_address {
+        return Err(IpamError::InvalidRequest("strategy=hashed places addresses by MAC address, which Docker only sends with REQUIRES_MAC_ADDRESS set".to_string()));
+    }
+
+    let pool_id = databases.transaction(|scope, schema| {
+        let selection = match conf.preferred_pool.is_empty() {
+            true => Scope::allocate_placed_pool(&requested_tags(&conf.options), conf.v6, strategy, key.as_deref(), scope)?,
+            false => {
+                let pool = IpCidr::from_str(&conf.preferred_pool)?;
+
+                if pool.is_ipv6() != conf.v6 {
+                    return Err(IpamError::InvalidRequest(format!("V6 is {} but Pool is {:#}", conf.v6, pool)));
+                }
+                let sub_pool = match conf.sub_pool.is_empty() {
+                    true => None,
+                    false => Some(IpCidr::from_str(&conf.sub_pool)?)
+                };
+
+                // a replay names the pool it was given the first time, dynamic or not
+                let pool_id = format!("{:#}", pool);
+                if config.requires_request_replay && Scope::replayed_pool(&pool_id, sub_pool, &request, scope)? {
+                    return Ok(pool_id);
+                }
+
+                Scope::claim_pool(pool, sub_pool, scope, schema)?
+            }
+        };
+        let pool_id = selection.pool_id()?;
+
+        Scope::remember_request(&pool_id, None, request, now, scope)?;
+        Ok(pool_id)
+    })?;
+
+    Ok(Json(RequestPoolResponse {
+        pool_id: pool_id.clone(),
+        pool: pool_id,
+        data: HashMap::new()
+    }))
+}
+
+#[post("/IpamDriver.ReleasePool", data = "<request>")]
+fn release_pool(request: Json<ReleasePoolRequest>, databases: State<Databases>) -> IpamResponse<EmptyResponse> {
+    let request = request.into_inner();
+    databases.transaction(|scope, _| Scope::release_pool(request.pool_id, scope))?;
+    Ok(Json(EmptyResponse {}))
+}
+
+#[post("/IpamDriver.RequestAddress", data = "<request>")]
+fn request_address(request: Json<RequestAddressRequest>, config: State<DriverConfig>, databases: State<Databases>) -> IpamResponse<RequestAddressResponse> {
+    let request = request.into_inner();
+    let address = match request.address.is_empty() {
+        true => None,
+        false => Some(request.address)
+    };
+
+    let options = request.options.unwrap_or_default();
+    let kind = match options.get(REQUEST_ADDRESS_TYPE).map_or(false, |t| t == GATEWAY_ADDRESS_TYPE) {
+        true => AddressKind::Gateway,
+        false => AddressKind::Endpoint
+    };
+    let identity = RequestIdentity {
+        options: options.into_iter().collect()
+    };
+
+    let now = config.clock.now();
+    let allocated = databases.transaction(|scope, _| {
+        let replayed = match (config.requires_request_replay, &address) {
+            (true, Some(address)) => Scope::replayed_address(&request.pool_id, address, kind, &identity, scope)?,
+            _ => None
+        };
+
+        let (replay, picked) = (replayed.is_some(), address.is_none());
+        let allocated = match (replayed, kind) {
+            (Some(replayed), _) => replayed,
+            (None, AddressKind::Gateway) => Scope::reserve_address(&request.pool_id, address, kind, now, scope)?,
+            (None, _) if address.is_none() => {
+                let mac = identity.options.get(MAC_ADDRESS_OPTION).map(|m| m.to_lowercase());
+                Scope::allocate_free_address(&request.pool_id, mac.as_deref(), config.sticky_mac_idle, config.address_reuse, now, scope)?
+            }
+            (None, _) => Scope::reserve_address(&request.pool_id, address, kind, now, scope)?
+        };
+
+        Scope::remember_request(&request.pool_id, Some(allocated.address()), identity, now, scope)?;
+
+        // only addresses the driver picked are leased, since libnetwork reserves --aux-address
+        // entries with a plain request for the address and never renews them. A replay renews the
+        // lease the endpoint already had
+        if let (AddressKind::Endpoint, Some(ttl)) = (kind, config.lease_ttl) {
+            if picked || (replay && Scope::is_leased(&request.pool_id, &allocated.address(), scope)?) {
+                Scope::renew_lease(&request.pool_id, &allocated.address(), now + ttl, now, scope)?;
+            }
+        }
+
+        Ok(allocated)
+    })?;
+
+    Ok(Json(RequestAddressResponse {
+        address: format!("{:#}", allocated),
+        data: HashMap::new()
+    }))
+}
+
+#[post("/IpamDriver.ReleaseAddress", data = "<request>")]
+fn release_address(request: Json<ReleaseAddressRequest>, config: State<DriverConfig>, databases: State<Databases>) -> IpamResponse<EmptyResponse> {
+    let request = request.into_inner();
+    let now = config.clock.now();
+    databases.transaction(|scope, _| Scope::release_address(request.pool_id, request.address, now, scope))?;
+    Ok(Json(EmptyResponse {}))
 }
 
-#[get("/IpamDriver.GetDefaultAddressSpaces")]
-fn get_default_address_spaces() -> &'static str {
-    todo!()
+#[post("/Admin.RenewLease", data = "<request>")]
+fn renew_lease(request: Json<RenewLeaseRequest>, config: State<DriverConfig>, databases: State<Databases>) -> IpamResponse<RenewLeaseResponse> {
+    let request = request.into_inner();
+    let address = util::string_to_ip_addr(request.address)?;
+
+    let ttl = match config.lease_ttl {
+        Some(ttl) => ttl,
+        None => return Err(IpamError::InvalidRequest("address leases are not enabled".to_string()))
+    };
+    let now = config.clock.now();
+    let expires = now + ttl;
+
+    databases.transaction(|scope, _| Scope::renew_lease(&request.pool_id, &address, expires, now, scope))?;
+
+    Ok(Json(RenewLeaseResponse {
+        address: address.to_string(),
+        expires: expires.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+    }))
 }
 
-#[post("/IpamDriver.RequestPool")]
-fn request_pool() -> &'static str {
-    todo!()
+#[post("/Admin.Lock", data = "<request>")]
+fn lock(request: Json<LockRequest>, databases: State<Databases>) -> IpamResponse<LockStatus> {
+    let request = request.into_inner();
+    let pool = IpCidr::from_str(&request.pool)?;
+
+    if request.by.trim().is_empty() {
+        return Err(IpamError::InvalidRequest("By is required to lock a pool".to_string()));
+    }
+
+    let lock = Some(Lock {
+        by: request.by,
+        reason: request.reason,
+        since: SystemTime::now()
+    });
+
+    databases.transaction(|scope, schema| util::set_lock(&pool, lock.clone(), scope, schema))?;
+    Ok(Json(lock_status(&pool, &lock)))
 }
 
-#[post("/IpamDriver.ReleasePool")]
-fn release_pool() -> &'static str {
-    todo!()
+#[post("/Admin.Unlock", data = "<request>")]
+fn unlock(request: Json<UnlockRequest>, databases: State<Databases>) -> IpamResponse<EmptyResponse> {
+    let pool = IpCidr::from_str(&request.into_inner().pool)?;
+    databases.transaction(|scope, schema| util::set_lock(&pool, None, scope, schema))?;
+    Ok(Json(EmptyResponse {}))
 }
 
-#[get("/IpamDriver.RequestAddress")]
-fn request_address() -> &'static str {
-    todo!()
+#[get("/Admin.Locks")]
+fn locks(databases: State<Databases>) -> IpamResponse<LocksResponse> {
+    let locks = databases.read(|scope, schema| util::locks(scope, schema))?;
+
+    Ok(Json(LocksResponse {
+        locks: locks
+            .iter()
+            .map(|(pool, lock)| lock_status(pool, lock))
+            .collect()
+    }))
 }
 
-#[get("/IpamDriver.ReleaseAddress")]
-fn release_address() -> &'static str {
-    todo!()
+fn rocket(mut config: DriverConfig, databases: Databases) -> Result<Rocket, Box<dyn Error>> {
+    config.requires_mac_address = databases.read(|_, schema| mac_address_required(&config, schema))?;
+
+    Ok(rocket::ignite()
+    .manage(config)
+    .manage(databases)
+    .mount("/", routes![
+        activate,
+        get_capabilities,
+        get_default_address_spaces,
+        request_pool,
+        release_pool,
+        request_address,
+        release_address,
+        renew_lease,
+        lock,
+        unlock,
+        locks]))
 }
 
-pub(crate) fn http_server() {
-    rocket::ignite()
-    .mount("/IpamDriver.GetDefaultAddressSpaces", routes![get_default_address_spaces])
-    .mount("/IpamDriver.RequestPool", routes![request_pool])
-    .mount("/IpamDriver.ReleasePool", routes![release_pool])
-    .mount("/IpamDriver.RequestAddress", routes![request_address]);
-}
\ No newline at end of file
+pub(crate) fn http_server(config: DriverConfig, databases: Databases) -> Result<(), Box<dyn Error>> {
+    match config.socket.clone() {
+        Some(socket) => {
+            socket::serve(rocket(config, databases)?, &socket)
+        }
+        None => {
+            Err(rocket(config, databases)?.launch().to_string().into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod protocol_tests {
+    use rocket::local::Client;
+    use crate::config::SchemaConfig;
+    use crate::http::*;
+    use crate::storage::MemoryStore;
+
+    fn memory_databases() -> Databases {
+        Databases::new(Box::new(MemoryStore::new()), Box::new(MemoryStore::new()))
+    }
+
+    #[test]
+    fn activate_implements_ipam_driver() {
+        let client = Client::new(rocket(DriverConfig::default(), memory_databases()).unwrap()).unwrap();
+        let mut response = client.post("/Plugin.Activate").dispatch();
+
+        assert_eq!(response.status(), http::Status::Ok);
+        assert_eq!(response.body_string().unwrap(), r#"{"Implements":["IpamDriver"]}"#);
+    }
+
+    #[test]
+    fn capabilities_follow_driver_config() {
+        let config = DriverConfig {
+            requires_mac_address: true,
+            ..DriverConfig::default()
+        };
+        let client = Client::new(rocket(config, memory_databases()).unwrap()).unwrap();
+        let mut response = client.post("/IpamDriver.GetCapabilities").dispatch();
+
+        assert_eq!(response.body_string().unwrap(), r#"{"RequiresMACAddress":true,"RequiresRequestReplay":false}"#);
+    }
+
+    #[test]
+    fn hashed_placement_asks_for_mac_addresses() {
+        let databases = memory_databases();
+        let request = r#"{"AddressSpace":"LocalDefault","Pool":"","SubPool":"","Options":{"strategy":"hashed","strategy-key":"net-a"},"V6":false}"#;
+
+        crate::database::initialize_databases(&databases, &SchemaConfig::default()).unwrap();
+        let client = Client::new(rocket(DriverConfig::default(), databases.clone()).unwrap()).unwrap();
+        let mut response = client.post("/IpamDriver.GetCapabilities").dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"RequiresMACAddress":false,"RequiresRequestReplay":false}"#);
+        assert_eq!(client.post("/IpamDriver.RequestPool").body(request).dispatch().status(), http::Status::BadRequest);
+
+        let config = SchemaConfig::from_toml("[[root]]\nnetwork = \"10.10.0.0/16\"\nprefix_length = 24\nstrategy = \"hashed\"").unwrap();
+        databases.transaction(|_, schema| Schema::seed(schema, &config)).unwrap();
+        let client = Client::new(rocket(DriverConfig::default(), databases.clone()).unwrap()).unwrap();
+        let mut response = client.post("/IpamDriver.GetCapabilities").dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"RequiresMACAddress":true,"RequiresRequestReplay":false}"#);
+        assert_eq!(client.post("/IpamDriver.RequestPool").body(request).dispatch().status(), http::Status::Ok);
+    }
+
+    #[test]
+    fn hashed_schema_serves_pools_without_options() {
+        let databases = memory_databases();
+        let client = Client::new(rocket(DriverConfig::default(), databases.clone()).unwrap()).unwrap();
+        let config = SchemaConfig::from_toml("[[root]]\nnetwork = \"10.10.0.0/16\"\nprefix_length = 24\nstrategy = \"hashed\"").unwrap();
+
+        databases.transaction(|scope, schema| {
+            Schema::seed(schema, &config)?;
+            util::create_initial_scopes(scope, schema)
+        }).unwrap();
+
+        let mut response = client.post("/IpamDriver.RequestPool")
+            .body(r#"{"AddressSpace":"LocalDefault","Pool":"","SubPool":"","Options":null,"V6":false}"#)
+            .dispatch();
+        assert_eq!(response.status(), http::Status::Ok);
+        assert_eq!(response.body_string().unwrap(), r#"{"PoolID":"10.10.0.0/24","Pool":"10.10.0.0/24","Data":{}}"#);
+    }
+
+    #[test]
+    fn every_route_is_mounted_at_root() {
+        let client = Client::new(rocket(DriverConfig::default(), memory_databases()).unwrap()).unwrap();
+        let mut response = client.post("/IpamDriver.GetDefaultAddressSpaces").dispatch();
+
+        assert_eq!(response.status(), http::Status::Ok);
+        assert_eq!(response.body_string().is_some(), true);
+
+        let paths: Vec<String> = client.rocket().routes().map(|r| r.uri.to_string()).collect();
+        assert_eq!(paths.contains(&"/IpamDriver.ReleaseAddress".to_string()), true);
+        assert_eq!(paths.contains(&"/IpamDriver.RequestAddress".to_string()), true);
+    }
+
+    #[test]
+    fn serves_over_unix_socket() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket = socket::SocketConfig {
+            path: dir.path().join("ipam.sock"),
+            owner: None,
+            group: None,
+            mode: None,
+            spec_file: Some(dir.path().join("ipam.spec"))
+        };
+        let config = DriverConfig {
+            socket: Some(socket.clone()),
+            ..DriverConfig::default()
+        };
+
+        std::thread::spawn(move || http_server(config, memory_databases()).unwrap());
+
+        let mut stream = loop {
+            match UnixStream::connect(&socket.path) {
+                Ok(stream) => break stream,
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10))
+            }
+        };
+
+        // one connection carries several requests, and chunked bodies are read like any other
+        stream.write_all(b"POST /Plugin.Activate HTTP/1.1\r\nHost: plugin\r\nContent-Length: 0\r\n\r\n").unwrap();
+        stream.write_all(b"POST /IpamDriver.RequestPool HTTP/1.1\r\nHost: plugin\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap();
+        stream.write_all(b"5d\r\n{\"AddressSpace\":\"LocalDefault\",\"Pool\":\"\",\"SubPool\":\"100.64.1.0/24\",\"Options\":null,\"V6\":false}\r\n0\r\n\r\n").unwrap();
+        stream.write_all(b"POST /Plugin.Activate HTTP/1.1\r\nHost: plugin\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert_eq!(response.starts_with("HTTP/1.1 200 OK"), true);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        assert_eq!(response.contains("SubPool requires Pool to be set"), true);
+        assert_eq!(response.ends_with(r#"{"Implements":["IpamDriver"]}"#), true);
+        assert_eq!(std::fs::read_to_string(socket.spec_file.unwrap()).unwrap(), format!("unix://{}", socket.path.display()));
+    }
+
+    #[test]
+    fn errors_use_err_envelope_and_status() {
+        let client = Client::new(rocket(DriverConfig::default(), memory_databases()).unwrap()).unwrap();
+        let mut response = client.post("/IpamDriver.RequestPool")
+            .body(r#"{"AddressSpace":"LocalDefault","Pool":"","SubPool":"100.64.1.0/24","Options":null,"V6":false}"#)
+            .dispatch();
+
+        assert_eq!(response.status(), http::Status::BadRequest);
+        assert_eq!(response.body_string().unwrap(), r#"{"Err":"invalid request: SubPool requires Pool to be set"}"#);
+    }
+
+    #[test]
+    fn handlers_share_managed_stores() {
+        let databases = memory_databases();
+        crate::database::initialize_databases(&databases, &SchemaConfig::default()).unwrap();
+        let client = Client::new(rocket(DriverConfig::default(), databases).unwrap()).unwrap();
+
+        let mut response = client.post("/IpamDriver.RequestPool")
+            .body(r#"{"AddressSpace":"LocalDefault","Pool":"","SubPool":"","Options":null,"V6":false}"#)
+            .dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"PoolID":"100.64.0.0/20","Pool":"100.64.0.0/20","Data":{}}"#);
+
+        // the gateway, then an --aux-address, the way libnetwork asks for them
+        let mut response = client.post("/IpamDriver.RequestAddress")
+            .body(r#"{"PoolID":"100.64.0.0/20","Address":"","Options":{"RequestAddressType":"com.docker.network.gateway"}}"#)
+            .dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"Address":"100.64.0.1/20","Data":{}}"#);
+
+        let mut response = client.post("/IpamDriver.RequestAddress")
+            .body(r#"{"PoolID":"100.64.0.0/20","Address":"100.64.0.2","Options":null}"#)
+            .dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"Address":"100.64.0.2/20","Data":{}}"#);
+
+        let mut response = client.post("/IpamDriver.RequestAddress")
+            .body(r#"{"PoolID":"100.64.0.0/20","Address":"","Options":null}"#)
+            .dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"Address":"100.64.0.3/20","Data":{}}"#);
+
+        let response = client.post("/IpamDriver.ReleaseAddress")
+            .body(r#"{"PoolID":"100.64.0.0/20","Address":"100.64.0.3"}"#)
+            .dispatch();
+        assert_eq!(response.status(), http::Status::Ok);
+
+        let mut response = client.post("/IpamDriver.RequestPool")
+            .body(r#"{"AddressSpace":"LocalDefault","Pool":"100.64.16.0/20","SubPool":"","Options":null,"V6":false}"#)
+            .dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"PoolID":"100.64.16.0/20","Pool":"100.64.16.0/20","Data":{}}"#);
+    }
+
+    #[test]
+    fn replayed_requests_get_the_same_allocation() {
+        let databases = memory_databases();
+        crate::database::initialize_databases(&databases, &SchemaConfig::default()).unwrap();
+        let config = DriverConfig {
+            requires_request_replay: true,
+            ..DriverConfig::default()
+        };
+        let client = Client::new(rocket(config, databases).unwrap()).unwrap();
+        let pool = r#"{"AddressSpace":"LocalDefault","Pool":"100.64.16.0/20","SubPool":"","Options":{"tags":"a"},"V6":false}"#;
+        let address = r#"{"PoolID":"100.64.16.0/20","Address":"100.64.16.5","Options":{"com.docker.network.endpoint.macaddress":"02:42:ac:11:00:02"}}"#;
+
+        for _ in 0..2 {
+            let mut response = client.post("/IpamDriver.RequestPool").body(pool).dispatch();
+            assert_eq!(response.body_string().unwrap(), r#"{"PoolID":"100.64.16.0/20","Pool":"100.64.16.0/20","Data":{}}"#);
+
+            let mut response = client.post("/IpamDriver.RequestAddress").body(address).dispatch();
+            assert_eq!(response.body_string().unwrap(), r#"{"Address":"100.64.16.5/20","Data":{}}"#);
+        }
+
+        let response = client.post("/IpamDriver.RequestPool")
+            .body(r#"{"AddressSpace":"LocalDefault","Pool":"100.64.16.0/20","SubPool":"","Options":null,"V6":false}"#)
+            .dispatch();
+        assert_eq!(response.status(), http::Status::Conflict);
+
+        let response = client.post("/IpamDriver.RequestAddress")
+            .body(r#"{"PoolID":"100.64.16.0/20","Address":"100.64.16.5","Options":null}"#)
+            .dispatch();
+        assert_eq!(response.status(), http::Status::Conflict);
+    }
+
+    #[test]
+    fn leases_are_renewed_and_swept() {
+        use std::time::Duration;
+        use crate::lease::{sweep, Clock, ManualClock};
+
+        let databases = memory_databases();
+        crate::database::initialize_databases(&databases, &SchemaConfig::default()).unwrap();
+        let clock = std::sync::Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let config = DriverConfig {
+            lease_ttl: Some(Duration::from_secs(60)),
+            clock: clock.clone(),
+            ..DriverConfig::default()
+        };
+        let client = Client::new(rocket(config, databases.clone()).unwrap()).unwrap();
+        let address = r#"{"PoolID":"100.64.0.0/20","Address":"","Options":null}"#;
+
+        client.post("/IpamDriver.RequestPool")
+            .body(r#"{"AddressSpace":"LocalDefault","Pool":"","SubPool":"","Options":null,"V6":false}"#)
+            .dispatch();
+        let mut response = client.post("/IpamDriver.RequestAddress").body(address).dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"Address":"100.64.0.1/20","Data":{}}"#);
+        let mut response = client.post("/IpamDriver.RequestAddress")
+            .body(r#"{"PoolID":"100.64.0.0/20","Address":"100.64.0.9","Options":null}"#)
+            .dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"Address":"100.64.0.9/20","Data":{}}"#);
+
+        clock.advance(Duration::from_secs(45));
+        let mut response = client.post("/Admin.RenewLease")
+            .body(r#"{"PoolID":"100.64.0.0/20","Address":"100.64.0.1"}"#)
+            .dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"Address":"100.64.0.1","Expires":1700000105}"#);
+        let modified = databases.transaction(|scope, _| Ok(Scope::select(&"100.64.0.0/20".to_string(), scope)?.actual.modified)).unwrap();
+        assert_eq!(modified, clock.now());
+
+        clock.advance(Duration::from_secs(45));
+        assert_eq!(sweep(&databases, clock.now()).unwrap(), Vec::new());
+
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(sweep(&databases, clock.now()).unwrap(), vec![("100.64.0.0/20".to_string(), "100.64.0.1".to_string())]);
+
+        let mut response = client.post("/IpamDriver.RequestAddress").body(address).dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"Address":"100.64.0.1/20","Data":{}}"#);
+
+        let response = client.post("/Admin.RenewLease")
+            .body(r#"{"PoolID":"100.64.0.0/20","Address":"100.64.0.10"}"#)
+            .dispatch();
+        assert_eq!(response.status(), http::Status::BadRequest);
+
+        // the requested address is never swept
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(sweep(&databases, clock.now()).unwrap(), vec![("100.64.0.0/20".to_string(), "100.64.0.1".to_string())]);
+    }
+
+    #[test]
+    fn cooldown_follows_the_driver_clock() {
+        use std::time::Duration;
+        use crate::lease::ManualClock;
+
+        let databases = memory_databases();
+        crate::database::initialize_databases(&databases, &SchemaConfig::default()).unwrap();
+        let clock = std::sync::Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let config = DriverConfig {
+            address_reuse: ReusePolicy::Cooldown(Duration::from_secs(60)),
+            clock: clock.clone(),
+            ..DriverConfig::default()
+        };
+        let client = Client::new(rocket(config, databases).unwrap()).unwrap();
+        let address = r#"{"PoolID":"100.64.0.0/20","Address":"","Options":null}"#;
+
+        client.post("/IpamDriver.RequestPool")
+            .body(r#"{"AddressSpace":"LocalDefault","Pool":"","SubPool":"","Options":null,"V6":false}"#)
+            .dispatch();
+        let mut response = client.post("/IpamDriver.RequestAddress").body(address).dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"Address":"100.64.0.1/20","Data":{}}"#);
+        client.post("/IpamDriver.ReleaseAddress")
+            .body(r#"{"PoolID":"100.64.0.0/20","Address":"100.64.0.1"}"#)
+            .dispatch();
+
+        let mut response = client.post("/IpamDriver.RequestAddress").body(address).dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"Address":"100.64.0.2/20","Data":{}}"#);
+
+        clock.advance(Duration::from_secs(60));
+        let mut response = client.post("/IpamDriver.RequestAddress").body(address).dispatch();
+        assert_eq!(response.body_string().unwrap(), r#"{"Address":"100.64.0.1/20","Data":{}}"#);
+    }
+
+    #[test]
+    fn lock_requires_by() {
+        let client = Client::new(rocket(DriverConfig::default(), memory_databases()).unwrap()).unwrap();
+        let mut response = client.post("/Admin.Lock")
+            .body(r#"{"Pool":"100.64.0.0/17","Reason":"maintenance"}"#)
+            .dispatch();
+
+        assert_eq!(response.status(), http::Status::BadRequest);
+        assert_eq!(response.body_string().unwrap(), r#"{"Err":"invalid request: By is required to lock a pool"}"#);
+    }
+
+    #[test]
+    fn lock_status_without_metadata() {
+        let pool = IpCidr::from_str("100.64.0.0/17").unwrap();
+        let response = serde_json::to_string(&lock_status(&pool, &None)).unwrap();
+
+        assert_eq!(response, r#"{"Pool":"100.64.0.0/17","By":null,"Reason":null,"Since":null}"#);
+    }
+
+    #[test]
+    fn ipam_conf_accepts_null_options() {
+        let conf: IpamConf = serde_json::from_str(
+            r#"{"AddressSpace":"LocalDefault","Pool":"","SubPool":"","Options":null,"V6":false}"#).unwrap();
+
+        assert_eq!(conf.address_space, LOCAL_DEFAULT_ADDRESS_SPACE);
+        assert_eq!(conf.options.is_none(), true);
+        assert_eq!(conf.v6, false);
+    }
+
+    #[test]
+    fn ipam_conf_reads_pool_and_sub_pool() {
+        let conf: IpamConf = serde_json::from_str(
+            r#"{"AddressSpace":"LocalDefault","Pool":"100.64.0.0/20","SubPool":"100.64.1.0/24","Options":{"tags":"a"},"V6":false}"#).unwrap();
+
+        assert_eq!(conf.preferred_pool, "100.64.0.0/20");
+        assert_eq!(conf.sub_pool, "100.64.1.0/24");
+        assert_eq!(conf.options.unwrap().get("tags").unwrap(), "a");
+    }
+
+    #[test]
+    fn tags_are_read_from_ipam_options() {
+        let conf: IpamConf = serde_json::from_str(
+            r#"{"AddressSpace":"LocalDefault","Options":{"tags":"team-a, edge,"},"V6":false}"#).unwrap();
+
+        assert_eq!(requested_tags(&conf.options), vec!["team-a", "edge"]);
+        assert_eq!(requested_tags(&None), Vec::<String>::new());
+    }
+
+    #[test]
+    fn request_pool_response_uses_libnetwork_field_names() {
+        let response = serde_json::to_string(&RequestPoolResponse {
+            pool_id: "100.64.0.0/20".to_string(),
+            pool: "100.64.0.0/20".to_string(),
+            data: HashMap::new()
+        }).unwrap();
+
+        assert_eq!(response, r#"{"PoolID":"100.64.0.0/20","Pool":"100.64.0.0/20","Data":{}}"#);
+    }
+
+    #[test]
+    fn default_address_spaces() {
+        let response = serde_json::to_string(&get_default_address_spaces().into_inner()).unwrap();
+
+        assert_eq!(response, r#"{"LocalDefaultAddressSpace":"LocalDefault","GlobalDefaultAddressSpace":"GlobalDefault"}"#);
+    }
+}
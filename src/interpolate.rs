@@ -1,11 +1,10 @@
-use std::error::Error;
 use std::time::SystemTime;
-use cidr::Cidr;
 use cidr::IpCidr;
 use log::warn;
+use crate::error::*;
 use crate::schema::*;
 use crate::scope::*;
-use crate::model::{SelectionOperation};
+use crate::model::{Family, SelectionOperation};
 use crate::{util, model::{Selection}};
 
 pub struct ProtoScope<T> {
@@ -13,18 +12,18 @@ pub struct ProtoScope<T> {
 }
 
 pub trait factory<T> {
-    fn null() -> Result<ProtoScope<T>, Box<dyn Error>>;
-    fn new_type_backed_proto_scope(backing: T) -> Result<ProtoScope<T>, Box<dyn Error>>;
+    fn null() -> IpamResult<ProtoScope<T>>;
+    fn new_type_backed_proto_scope(backing: T) -> IpamResult<ProtoScope<T>>;
 }
 
 impl factory<IpCidr> for ProtoScope<IpCidr> {
-    fn null() -> Result<ProtoScope<IpCidr>, Box<dyn Error>> {
+    fn null() -> IpamResult<ProtoScope<IpCidr>> {
         Ok(ProtoScope{
             cidr: None,
         })
     }
 
-    fn new_type_backed_proto_scope(backing: IpCidr) -> Result<ProtoScope<IpCidr>, Box<dyn Error>> {
+    fn new_type_backed_proto_scope(backing: IpCidr) -> IpamResult<ProtoScope<IpCidr>> {
         Ok(ProtoScope { 
             cidr: Some(backing) 
         })
@@ -32,11 +31,12 @@ impl factory<IpCidr> for ProtoScope<IpCidr> {
 }
 
 impl ProtoScope<IpCidr> {
-    pub fn scope_from_proto_scope(&self, _parent: Option<&mut Selection<Scope>>) -> Result<Selection<Scope>, Box<dyn Error>> {    
+    pub fn scope_from_proto_scope(&self, _parent: Option<&mut Selection<Scope>>) -> IpamResult<Selection<Scope>> {    
         match self.cidr {
             Some(v) => Ok(Selection {
                 actual: Scope {
-                    id: util::string_to_u128_id(v.to_string())?,
+                    id: util::string_to_u128_id(v.first_address().to_string())?,
+                    family: Family::of(&v.first_address()),
                     parent: match _parent {
                         Some(parent) => {
                             Some(parent.actual.id)
@@ -46,33 +46,53 @@ impl ProtoScope<IpCidr> {
                     modified: SystemTime::now(),
                     created: SystemTime::now(),
                     descriptions: Vec::new(),
+                    revision: 0
                 },
                 selected_prefix_length: Some(v.network_length()),
                 saved: false,
                 operation: SelectionOperation::DEFAULT,
             }),
-            None => Err("uninitalized protoscope can't convert to scope".into())
+            None => Err(IpamError::InvalidCidr("uninitalized protoscope can't convert to scope".to_string()))
         }
     }
 
-    pub fn schema_from_proto_scope(&self, _parent: Option<&mut Selection<Schema>>) -> Result<Selection<Schema>, Box<dyn Error>> {    
+    pub fn schema_from_proto_scope(&self, _parent: Option<&mut Selection<Schema>>) -> IpamResult<Selection<Schema>> {    
         match self.cidr {
             Some(v) => {
+                let pool = util::string_to_u128_id(v.first_address().to_string())?;
+                let (parent, tags, locked, strategy) = match _parent {
+                    Some(parent) => {
+                        match parent.selected_description().or(parent.actual.descriptions.first()) {
+                            Some(description) => (Some(parent.actual.pool), description.tags.clone(), description.locked, description.strategy),
+                            None => (Some(parent.actual.pool), Vec::new(), false, None)
+                        }
+                    }
+                    None => (None, Vec::new(), false, None),
+                };
+
                 Ok(Selection {
                     actual: Schema {
-                        pool: util::string_to_u128_id(v.first_address().to_string())?,
-                        descriptions: [None, None],
-                        parent: match _parent {
-                            Some(parent) => Some(parent.actual.pool),
-                            None => None,
-                        }
+                        pool: pool,
+                        family: Family::of(&v.first_address()),
+                        descriptions: vec![SchemaDescription {
+                            prefix_length: v.network_length(),
+                            allocation_prefix_length: v.network_length(),
+                            locked: locked,
+                            tags: tags,
+                            lock: None,
+                            strategy: strategy
+                        }],
+                        parent: parent
                     },
                     selected_prefix_length: Some(v.network_length()),
                     saved: false,
-                    operation: SelectionOperation::DEFAULT,
+                    operation: match parent == Some(pool) {
+                        true => SelectionOperation::UPDATE_PARENT_DESCRIPTIONS,
+                        false => SelectionOperation::DEFAULT
+                    },
                 })
             }
-            None => Err("uninitalized protoscope can't convert to schema".into())
+            None => Err(IpamError::InvalidCidr("uninitalized protoscope can't convert to schema".to_string()))
         }
     }
 
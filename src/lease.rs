@@ -0,0 +1,112 @@
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use log::{info, warn};
+use crate::database::Databases;
+use crate::error::*;
+use crate::scope::Scope;
+
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// a clock that only moves when told to, so expiry can be tested without sleeping
+#[cfg(test)]
+pub(crate) struct ManualClock {
+    now: std::sync::Mutex<SystemTime>
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(now: SystemTime) -> ManualClock {
+        ManualClock {
+            now: std::sync::Mutex::new(now)
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+// reclaims every lease that ran out by now, returns the pool and address of each
+pub(crate) fn sweep(databases: &Databases, now: SystemTime) -> IpamResult<Vec<(String, String)>> {
+    databases.transaction(|scope, _| Scope::expire_leases(now, scope))
+}
+
+// sweeps on its own thread for as long as the driver runs; a failed sweep is retried next interval
+pub(crate) fn spawn_sweeper(databases: Databases, clock: std::sync::Arc<dyn Clock>, interval: Duration) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(interval);
+
+            match sweep(&databases, clock.now()) {
+                Ok(expired) => {
+                    for (pool_id, address) in expired {
+                        info!("lease on {} in {} expired", address, pool_id);
+                    }
+                }
+                Err(e) => {
+                    warn!("lease sweep failed: {}", e);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod lease_tests {
+    use crate::config::SchemaConfig;
+    use crate::database::initialize_databases;
+    use crate::lease::*;
+    use crate::model::*;
+    use crate::storage::MemoryStore;
+
+    #[test]
+    fn sweep_reclaims_expired_leases() {
+        let databases = Databases::new(Box::new(MemoryStore::new()), Box::new(MemoryStore::new()));
+        initialize_databases(&databases, &SchemaConfig::default()).unwrap();
+        let clock = ManualClock::new(SystemTime::now());
+
+        let pool_id = databases.transaction(|scope, _| Scope::allocate_pool(Vec::new(), false, scope)?.pool_id()).unwrap();
+        let (short, long) = databases.transaction(|scope, _| {
+            let short = Scope::allocate_address(pool_id.clone(), None, scope)?.address();
+            let long = Scope::allocate_address(pool_id.clone(), None, scope)?.address();
+
+            Scope::renew_lease(&pool_id, &short, clock.now() + Duration::from_secs(60), clock.now(), scope)?;
+            Scope::renew_lease(&pool_id, &long, clock.now() + Duration::from_secs(600), clock.now(), scope)?;
+            Ok((short, long))
+        }).unwrap();
+
+        assert_eq!(sweep(&databases, clock.now()).unwrap(), Vec::new());
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(sweep(&databases, clock.now()).unwrap(), vec![(pool_id.clone(), short.to_string())]);
+
+        let remaining = databases.transaction(|scope, _| {
+            Ok(Scope::select(&pool_id, scope)?
+                .selected_description()
+                .unwrap()
+                .addresses
+                .iter()
+                .map(|a| a.address.clone())
+                .collect::<Vec<String>>())
+        }).unwrap();
+        assert_eq!(remaining, vec![long.to_string()]);
+    }
+}
@@ -4,7 +4,10 @@
 #![feature(decl_macro)]
 
 use std::error::Error;
-use database::initialize_databases;
+use config::{DriverConfig, SchemaConfig};
+use database::{initialize_databases, Databases};
+use error::IpamError;
+use http::http_server;
 extern crate core;
 
 mod scope;
@@ -15,8 +18,24 @@ mod error;
 mod http;
 mod database; 
 mod util;
+mod config;
+mod socket;
+mod storage;
+mod lease;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    initialize_databases()?;
-    Ok(())
+    let config = DriverConfig::from_env()?;
+
+    // read on every start, so a bad config fails here even once the schema is seeded
+    let schema_config = SchemaConfig::from_env().map_err(|e| IpamError::SchemaInit(e.to_string()))?;
+
+    let databases = Databases::open()?;
+
+    initialize_databases(&databases, &schema_config)?;
+
+    if config.lease_ttl.is_some() {
+        lease::spawn_sweeper(databases.clone(), config.clock.clone(), config.lease_sweep_interval);
+    }
+
+    http_server(config, databases)
 }
\ No newline at end of file
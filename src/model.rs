@@ -1,7 +1,74 @@
-use std::error::Error;
-use cidr::IpCidr;
-use unqlite::UnQLite;
+use std::net::IpAddr;
+use std::time::SystemTime;
+use cidr::{IpCidr, IpInet};
+use crate::error::{IpamError, IpamResult};
 use crate::interpolate::ProtoScope;
+use crate::storage::Store;
+use crate::util;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Family {
+    V4,
+    V6
+}
+
+impl Family {
+    pub fn of(address: &IpAddr) -> Family {
+        match address {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6
+        }
+    }
+
+    // keys are a family tag byte followed by the big endian id, so 0.0.0.5 and ::5 don't collide
+    pub fn key(&self, id: u128) -> Vec<u8> {
+        let mut key = vec![match self {
+            Family::V4 => 4,
+            Family::V6 => 6
+        }];
+        key.extend_from_slice(&id.to_be_bytes());
+        key
+    }
+}
+
+// how a free pool or host address is picked. Random and hashed placement keep hosts that don't
+// coordinate from all starting at the same address
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    FirstFit,
+    Random,
+    Hashed
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Strategy::FirstFit
+    }
+}
+
+impl Strategy {
+    pub(crate) fn parse(name: &str) -> IpamResult<Strategy> {
+        match name {
+            "first-fit" => Ok(Strategy::FirstFit),
+            "random" => Ok(Strategy::Random),
+            "hashed" => Ok(Strategy::Hashed),
+            other => Err(IpamError::InvalidRequest(format!("unknown strategy {}, expected first-fit, random or hashed", other)))
+        }
+    }
+
+    // where to start among count candidates; callers scan on from there and wrap around, so every
+    // candidate is still tried. None when a hashed strategy has no key to hash
+    pub fn start(&self, count: u128, key: Option<&str>) -> Option<u128> {
+        match (self, key) {
+            (Strategy::Hashed, None) => None,
+            _ if count == 0 => Some(0),
+            (Strategy::FirstFit, _) => Some(0),
+            (Strategy::Random, _) => Some(rand::random::<u128>() % count),
+            (Strategy::Hashed, Some(key)) => Some(u128::from(util::stable_hash(key)) % count)
+        }
+    }
+}
 
 pub enum SelectionOperation {
     UPDATE_PARENT_DESCRIPTIONS,
@@ -16,33 +83,38 @@ pub struct Selection<RECORD_TYPE> {
 }
 
 pub trait data_operations<RECORD_TYPE, DESCRIPTION_TYPE> {
-    fn begin_tx(db: &mut UnQLite) -> Result<(), unqlite::Error>;
-    fn roll_back_tx(db: &mut UnQLite) -> Result<(), unqlite::Error>;
-    fn commit(db: &mut UnQLite) -> Result<(), unqlite::Error>;
-    fn initialize_db(db: &mut UnQLite) -> Result<(), Box<dyn Error>>;
-    fn dao() -> Result<UnQLite, Box<dyn Error>>;
-    fn save(s: &mut Selection<RECORD_TYPE>, db: &mut UnQLite) -> Result<(), Box<dyn Error>>;
-    fn exists_in_database(id: u128) -> Result<bool, Box<dyn Error>>;
-    fn retrieve_all() -> Result<Vec<Selection<RECORD_TYPE>>, Box<dyn Error>>;
-    fn allocate_pool(tags: Vec<String>) -> Result<Selection<RECORD_TYPE>, Box<dyn Error>>;
-    fn allocate_address(network: String) -> Result<Selection<RECORD_TYPE>, Box<dyn Error>>;
-    fn release_pool(network: String) -> Result<(), Box<dyn Error>>;
-    fn release_address(network: String) -> Result<(), Box<dyn Error>>;
-    fn is_db_initialized(db: &mut UnQLite) -> Result<bool, Box<dyn Error>>;
+    fn initialize_db(db: &mut dyn Store) -> IpamResult<()>;
+    fn dao() -> IpamResult<Box<dyn Store>>;
+    fn save(s: &mut Selection<RECORD_TYPE>, db: &mut dyn Store) -> IpamResult<()>;
+    fn exists_in_database(family: Family, id: u128, db: &dyn Store) -> IpamResult<bool>;
+    fn retrieve_all(db: &dyn Store) -> IpamResult<Vec<Selection<RECORD_TYPE>>>;
+    fn allocate_pool(tags: Vec<String>, v6: bool, db: &mut dyn Store) -> IpamResult<Selection<RECORD_TYPE>>;
+    fn allocate_address(pool_id: String, address: Option<String>, db: &mut dyn Store) -> IpamResult<IpInet>;
+    fn release_pool(pool_id: String, db: &mut dyn Store) -> IpamResult<()>;
+    fn release_address(pool_id: String, address: String, now: SystemTime, db: &mut dyn Store) -> IpamResult<()>;
+    fn is_db_initialized(db: &mut dyn Store) -> IpamResult<bool>;
+}
+
+// who locked a description and why; a lock covers everything beneath the locked pool
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct Lock {
+    pub by: String,
+    pub reason: String,
+    pub since: SystemTime
 }
 
 pub trait locking_operations {
-    fn lock(&self) -> Result<bool, Box<dyn Error>>;
-    fn unlock(&self) -> Result<bool, Box<dyn Error>>;
-    fn is_locked(&self) -> Result<bool, Box<dyn Error>>;
+    fn lock(&mut self, lock: Lock) -> IpamResult<bool>;
+    fn unlock(&mut self) -> IpamResult<bool>;
+    fn is_locked(&self) -> IpamResult<bool>;
 }
 
 pub trait factory<RECORD_TYPE, DESCRIPTION_TYPE, FROM_SELECTION_TYPE, FROM_SELECTION_DESCRIPTION_TYPE> {
-    fn new_from_string(network: String, prefix_length: u8, parent: Option<&mut Selection<RECORD_TYPE>>) -> Result<Selection<RECORD_TYPE>, Box<dyn Error>>;
-    fn new_from_bytes(network: Vec<u8>, prefix_length: u8, parent: Option<&mut Selection<RECORD_TYPE>>) -> Result<Selection<RECORD_TYPE>, Box<dyn Error>>;
-    fn new_from_proto_scope(network: ProtoScope<IpCidr>, parent: Option<&mut Selection<RECORD_TYPE>>) -> Result<Selection<RECORD_TYPE>, Box<dyn Error>>;
-    fn new_from_selection(network: Selection<FROM_SELECTION_TYPE>) -> Result<Selection<RECORD_TYPE>, Box<dyn Error>>;
-    fn new_from_json(json: String) -> Result<Selection<RECORD_TYPE>, Box<dyn Error>>;
-    fn to_proto_scope(&self) -> Result<ProtoScope<IpCidr>, Box<dyn Error>>;
-    fn new_selection(&self) -> Result<Selection<RECORD_TYPE>, Box<dyn Error>>;
+    fn new_from_string(network: String, prefix_length: u8, parent: Option<&mut Selection<RECORD_TYPE>>) -> IpamResult<Selection<RECORD_TYPE>>;
+    fn new_from_bytes(network: Vec<u8>, prefix_length: u8, parent: Option<&mut Selection<RECORD_TYPE>>) -> IpamResult<Selection<RECORD_TYPE>>;
+    fn new_from_proto_scope(network: ProtoScope<IpCidr>, parent: Option<&mut Selection<RECORD_TYPE>>) -> IpamResult<Selection<RECORD_TYPE>>;
+    fn new_from_selection(network: Selection<FROM_SELECTION_TYPE>) -> IpamResult<Selection<RECORD_TYPE>>;
+    fn new_from_json(json: String) -> IpamResult<Selection<RECORD_TYPE>>;
+    fn to_proto_scope(&self) -> IpamResult<ProtoScope<IpCidr>>;
+    fn new_selection(&self) -> IpamResult<Selection<RECORD_TYPE>>;
 }
@@ -1,180 +1,105 @@
-use std::error::Error;
-use std::net::{Ipv4Addr, Ipv6Addr};
-use cidr::IpCidr;
-use unqlite::{Cursor, KV, Transaction, UnQLite};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::SystemTime;
+use cidr::{IpCidr, IpInet};
+use crate::config::{SchemaConfig, SchemaLevel, SchemaRoot, ULA_NETWORK};
 use crate::model::*;
 use crate::error::*;
 use crate::interpolate::{factory as faktory, ProtoScope};
 use crate::scope::*;
+use crate::storage::{open_store, Store};
 use crate::util;
 
-#[derive(serde::Serialize, serde::Deserialize, Copy, Clone)]
+// every node is stored as a record, so cap a root at 65536 leaves (a /48 split into /64s)
+const MAX_SPLIT_BITS: u8 = 16;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct SchemaDescription {
     pub prefix_length: u8,
     pub allocation_prefix_length: u8,
-    pub locked: bool
+    pub locked: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub lock: Option<Lock>,
+    #[serde(default)]
+    pub strategy: Option<Strategy>
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Copy, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct Schema {
     pub pool: u128,
-    pub descriptions: [Option<SchemaDescription>; 2],
+    pub family: Family,
+    #[serde(deserialize_with = "flatten_descriptions")]
+    pub descriptions: Vec<SchemaDescription>,
     pub parent: Option<u128>,
 
 }
 
-impl data_operations<Schema, SchemaDescription> for Schema {
-    fn begin_tx(db: &mut UnQLite) -> Result<(), unqlite::Error> {
-        match db.begin() {
-            Ok(_) => {
-                Ok(())
-            }
-            Err(e) => Err(e)
-        }
-    }
-
-    fn roll_back_tx(db: &mut UnQLite) -> Result<(), unqlite::Error> {
-        match db.rollback() {
-            Ok(_) => {
-                Ok(())
-            }
-            Err(e) => Err(e)
-        }
-    }
-
-    fn commit(db: &mut UnQLite) -> Result<(), unqlite::Error> {
-        match db.commit() {
-            Ok(_) => {                
-                Ok(())
-            }
-            Err(e) => Err(e)
-        }
-    }
+// records written before schemas could nest deeper than two levels hold a [Option<_>; 2]
+fn flatten_descriptions<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<SchemaDescription>, D::Error> {
+    let descriptions: Vec<Option<SchemaDescription>> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(descriptions.into_iter().flatten().collect())
+}
 
-    fn initialize_db(db: &mut UnQLite) -> Result<(), Box<dyn Error>> {
-        match Schema::is_db_initialized(db)? {
-            true => {
-                Ok(())
-            }
-            false => {
-                let mut s = Schema::new_from_string(
-                    "100.64.0.0".to_string(),
-                    17,
-                    None)?;
-
-                match s.actual.to_proto_scope()?
-                    .children(20)
-                    .map(|f| -> Result<Selection<Schema>, Box<dyn Error>> {
-                        let mut child = Schema::new_from_proto_scope(
-                            f,
-                            Some(&mut s))?;
-
-                         Ok(match child.operation {
-                            SelectionOperation::UPDATE_PARENT_DESCRIPTIONS => {
-                                s.actual.descriptions[1] = child.actual.descriptions[0];
-                                Schema::save(&mut s, db)?;
-                                child
-                            }
-                            SelectionOperation::DEFAULT => {
-                                Schema::save(&mut child, db)?;
-                                child
-                            }
-                        })
-                    })
-                    .any(|selection| -> bool {
-                        selection.is_err()                        
-                    }) {
-                    true => {
-                        Err(DBSaveError.into())
-                    }
-                    false => {
-                        Ok(())
-                    }
-                }
-            }
-        }
+impl data_operations<Schema, SchemaDescription> for Schema {
+    // the driver seeds from the config main loaded, through Schema::initialize_with
+    fn initialize_db(db: &mut dyn Store) -> IpamResult<()> {
+        Schema::initialize_with(db, &SchemaConfig::default())
     }
 
-    fn dao() -> Result<UnQLite, Box<dyn Error>> {
+    fn dao() -> IpamResult<Box<dyn Store>> {
         match std::env::var("SCHEMA_DB_FILE") {
-            Ok(_) => {
-                if std::env::var("SCHEMA_DB_FILE")? == "" {                    
-                    Ok(UnQLite::create_temp())
-                }
-                else {
-                    Ok(UnQLite::create(std::env::var("SCHEMA_DB_FILE")?))
-                }
-            }
-            Err(e) => Err(Box::new(e))
-        }        
+            Ok(path) => open_store(&path),
+            Err(e) => Err(IpamError::Storage(format!("SCHEMA_DB_FILE: {}", e)))
+        }
     }
 
-    fn save(s: &mut Selection<Schema>, db: &mut UnQLite) -> Result<(), Box<dyn Error>> {
+    fn save(s: &mut Selection<Schema>, db: &mut dyn Store) -> IpamResult<()> {
         s.saved = true;
 
-        match db.kv_store(
-            s.actual.pool.to_be_bytes(),
+        match db.store(
+            &s.actual.family.key(s.actual.pool),
             serde_json::to_string(&mut s.actual)?.as_bytes()) {
             Ok(_) => {
                 Ok(())
             }
-            Err(_) => {
-                todo!()
+            Err(e) => {
+                Err(IpamError::Storage(format!("failed to save schema {}: {}", s.actual.pool, e)))
             }
         }
     }
 
-    fn exists_in_database(_id: u128) -> Result<bool, Box<dyn Error>> {
-        todo!()
+    fn exists_in_database(family: Family, id: u128, db: &dyn Store) -> IpamResult<bool> {
+        db.contains(&family.key(id))
     }
 
-    fn retrieve_all() -> Result<Vec<Selection<Schema>>, Box<dyn Error>> {
-        let db = Schema::dao().unwrap();
-        let mut entry = db.first();
-        let mut ret: Vec<Selection<Schema>> = Vec::new();
-
-        loop {
-            if entry.is_none() {
-               break;
-            }
-            else {
-                let record = entry.expect("valid entry");
-                let (_key, mut value) = record.key_value();
-
-                let v = String::from_utf8_lossy(value.as_mut_slice()).to_string();
-                let selection = Schema::new_from_json(v)?;
-
-                ret.push(selection);
-
-                entry = record.next();
-            }
-        }
-
-        Ok(ret)
+    fn retrieve_all(db: &dyn Store) -> IpamResult<Vec<Selection<Schema>>> {
+        Schema::records(db)
     }
 
-    fn allocate_pool(_tags: Vec<String>) -> Result<Selection<Schema>, Box<dyn Error>> {
+    fn allocate_pool(_tags: Vec<String>, _v6: bool, _db: &mut dyn Store) -> IpamResult<Selection<Schema>> {
         todo!("not implemented for schema")
     }
 
-    fn allocate_address(_network: String) -> Result<Selection<Schema>, Box<dyn Error>> {
+    fn allocate_address(_pool_id: String, _address: Option<String>, _db: &mut dyn Store) -> IpamResult<IpInet> {
         todo!("not implemented for schema")
     }
 
-    fn release_pool(_network: String) -> Result<(), Box<dyn Error>> {
+    fn release_pool(_pool_id: String, _db: &mut dyn Store) -> IpamResult<()> {
         todo!("not implemented for schema")
     }
 
-    fn release_address(_network: String) -> Result<(), Box<dyn Error>> {
+    fn release_address(_pool_id: String, _address: String, _now: SystemTime, _db: &mut dyn Store) -> IpamResult<()> {
         todo!("not implemented for schema")
     }
 
-    fn is_db_initialized(db: &mut UnQLite) -> Result<bool, Box<dyn Error>> {
-        match db.first() {
-            None => {
+    fn is_db_initialized(db: &mut dyn Store) -> IpamResult<bool> {
+        match db.is_empty()? {
+            true => {
                 Ok(false)
             }
-            Some(_) => {
+            false => {
                 Ok(true)
             }
         }
@@ -182,7 +107,7 @@ impl data_operations<Schema, SchemaDescription> for Schema {
 }
 
 impl crate::model::factory<Schema, SchemaDescription, Scope, ScopeDescription> for Schema {
-    fn new_from_string(network: String, prefix_length: u8, parent: Option<&mut Selection<Schema>>) -> Result<Selection<Schema>, Box<dyn Error>> {
+    fn new_from_string(network: String, prefix_length: u8, parent: Option<&mut Selection<Schema>>) -> IpamResult<Selection<Schema>> {
         let net = util::string_to_ip_cidr(network, prefix_length)?;
 
         let parent_id = match parent {
@@ -199,11 +124,15 @@ impl crate::model::factory<Schema, SchemaDescription, Scope, ScopeDescription> f
         Ok(Selection {
             actual: Schema {
                 pool: util::string_to_u128_id(net.first_address().to_string())?,
-                descriptions: [Some(SchemaDescription {
+                family: Family::of(&net.first_address()),
+                descriptions: vec![SchemaDescription {
                     prefix_length,
                     allocation_prefix_length: net.network_length(),
-                    locked: false
-                }), None],
+                    locked: false,
+                    tags: Vec::new(),
+                    lock: None,
+                    strategy: None
+                }],
                 parent: parent_id
             },
             selected_prefix_length: Some(prefix_length),
@@ -216,15 +145,15 @@ impl crate::model::factory<Schema, SchemaDescription, Scope, ScopeDescription> f
         })
     }
 
-    fn new_from_bytes(_network: Vec<u8>, _prefix_length: u8, _parent: Option<&mut Selection<Schema>>) -> Result<Selection<Schema>, Box<dyn Error>> {
+    fn new_from_bytes(_network: Vec<u8>, _prefix_length: u8, _parent: Option<&mut Selection<Schema>>) -> IpamResult<Selection<Schema>> {
         todo!()
     }
     
-    fn new_from_selection(_network: Selection<Scope>) -> Result<Selection<Schema>, Box<dyn Error>> {
+    fn new_from_selection(_network: Selection<Scope>) -> IpamResult<Selection<Schema>> {
         todo!()
     }
 
-    fn new_from_json(mut json: std::string::String) -> Result<Selection<Schema>, Box<dyn Error>> {
+    fn new_from_json(mut json: std::string::String) -> IpamResult<Selection<Schema>> {
         Ok(Selection {
             actual: serde_json::from_str(&mut json)?,
             selected_prefix_length: Option::None,
@@ -233,56 +162,344 @@ impl crate::model::factory<Schema, SchemaDescription, Scope, ScopeDescription> f
         })
     }
 
-    fn to_proto_scope(&self) -> Result<ProtoScope<IpCidr>, Box<dyn Error>> {
-       match self.pool > u32::MAX.into() {
-            true => {
-                ProtoScope::new_type_backed_proto_scope(
-                    IpCidr::new(
-                        Ipv6Addr::from(self.pool).into(),
-                        self.descriptions[0].unwrap().prefix_length)?)
-            },
-            false => {
+    fn to_proto_scope(&self) -> IpamResult<ProtoScope<IpCidr>> {
+       match self.descriptions.first() {
+            Some(description) => {
                 ProtoScope::new_type_backed_proto_scope(
-                    IpCidr::new(
-                        Ipv4Addr::from(self.pool as u32).into(),
-                        self.descriptions[0].unwrap().prefix_length)?)
-            },
+                    util::id_to_ip_cidr(self.family, self.pool, description.prefix_length)?)
+            }
+            None => Err(IpamError::InvalidCidr(format!("schema {} has no descriptions", self.pool)))
        }
     }
 
-    fn new_selection(&self) -> Result<Selection<Schema>, Box<dyn Error>> {
+    fn new_selection(&self) -> IpamResult<Selection<Schema>> {
         todo!()
     }
 
-    fn new_from_proto_scope(network: ProtoScope<IpCidr>, parent: Option<&mut Selection<Schema>>) -> Result<Selection<Schema>, Box<dyn Error>> {
+    fn new_from_proto_scope(network: ProtoScope<IpCidr>, parent: Option<&mut Selection<Schema>>) -> IpamResult<Selection<Schema>> {
         network.schema_from_proto_scope(parent)
     }
 }
 
+impl Schema {
+    pub fn initialize_with(db: &mut dyn Store, config: &SchemaConfig) -> IpamResult<()> {
+        util::migrate_record_keys(db)?;
+
+        match Schema::is_db_initialized(db)? {
+            true => {
+                Ok(())
+            }
+            false => {
+                Schema::seed(db, config)
+            }
+        }
+    }
+
+    pub fn seed(db: &mut dyn Store, config: &SchemaConfig) -> IpamResult<()> {
+        let mut seeded: Vec<IpCidr> = Vec::new();
+
+        for root in &config.roots {
+            let network = match root.network.as_str() {
+                ULA_NETWORK => util::generate_ula_prefix()?,
+                _ => match IpCidr::from_str(&root.network) {
+                    Ok(network) => network,
+                    Err(e) => return Err(IpamError::SchemaInit(format!("root {}: {}", root.network, e)))
+                }
+            };
+
+            let levels = root.levels();
+            let mut previous = network.network_length();
+
+            if levels.is_empty() {
+                return Err(IpamError::SchemaInit(format!(
+                    "root {} needs a prefix_length or at least one [[root.level]]", root.network)));
+            }
+
+            for level in &levels {
+                if level.prefix_length <= previous
+                    || util::string_to_ip_cidr(network.first_address().to_string(), level.prefix_length).is_err() {
+                    return Err(IpamError::SchemaInit(format!(
+                        "root {} can't split /{} pools into /{} pools", root.network, previous, level.prefix_length)));
+                }
+
+                previous = level.prefix_length;
+            }
+
+            if previous - network.network_length() > MAX_SPLIT_BITS {
+                return Err(IpamError::SchemaInit(format!(
+                    "root {} split into /{} pools would create more than {} pools", root.network, previous, 1 << MAX_SPLIT_BITS)));
+            }
+
+            if seeded.iter().any(|s| util::overlaps(s, &network)) {
+                return Err(IpamError::SchemaInit(format!("root {} overlaps another root", root.network)));
+            }
+
+            seeded.push(network);
+            Schema::seed_root(db, network, root)?;
+        }
+
+        Ok(())
+    }
+
+    fn seed_root(db: &mut dyn Store, network: IpCidr, root: &SchemaRoot) -> IpamResult<()> {
+        let levels = root.levels();
+        let mut records: BTreeMap<u128, Schema> = BTreeMap::new();
+
+        Schema::seed_node(&mut records, network, SchemaDescription {
+            prefix_length: network.network_length(),
+            allocation_prefix_length: levels.first().map_or(network.network_length(), |l| l.prefix_length),
+            locked: root.locked,
+            tags: root.tags.clone(),
+            lock: None,
+            strategy: root.strategy
+        }, None, &levels)?;
+
+        for (_, record) in records {
+            let mut selection = Selection {
+                actual: record,
+                selected_prefix_length: None,
+                saved: false,
+                operation: SelectionOperation::DEFAULT
+            };
+
+            if let Err(e) = Schema::save(&mut selection, db) {
+                return Err(IpamError::SchemaInit(e.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // nodes sharing a first address share a record, holding one description per level
+    fn seed_node(records: &mut BTreeMap<u128, Schema>, cidr: IpCidr, description: SchemaDescription, parent: Option<u128>, levels: &[SchemaLevel]) -> IpamResult<()> {
+        let id = util::string_to_u128_id(cidr.first_address().to_string())?;
+
+        records.entry(id)
+            .or_insert(Schema {
+                pool: id,
+                family: Family::of(&cidr.first_address()),
+                descriptions: Vec::new(),
+                parent
+            })
+            .descriptions
+            .push(description.clone());
+
+        if let Some((level, rest)) = levels.split_first() {
+            for child in ProtoScope::new_type_backed_proto_scope(cidr)?.children(level.prefix_length) {
+                let child_description = SchemaDescription {
+                    prefix_length: level.prefix_length,
+                    allocation_prefix_length: rest.first().map_or(level.prefix_length, |l| l.prefix_length),
+                    locked: description.locked || level.locked,
+                    tags: match level.tags.is_empty() {
+                        true => description.tags.clone(),
+                        false => level.tags.clone()
+                    },
+                    lock: None,
+                    strategy: level.strategy.or(description.strategy)
+                };
+
+                match child.cidr {
+                    Some(child_cidr) => Schema::seed_node(records, child_cidr, child_description, Some(id), rest)?,
+                    None => return Err(IpamError::SchemaInit(format!("failed to split {:#}", cidr)))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn records(db: &dyn Store) -> IpamResult<Vec<Selection<Schema>>> {
+        let mut ret: Vec<Selection<Schema>> = Vec::new();
+
+        for (_key, value) in db.entries()? {
+            ret.push(Schema::new_from_json(String::from_utf8_lossy(&value).to_string())?);
+        }
+
+        Ok(ret)
+    }
+
+    pub fn containing(pool: &IpCidr, db: &dyn Store) -> IpamResult<Option<Selection<Schema>>> {
+        let mut deepest: Option<Selection<Schema>> = None;
+
+        for mut selection in Schema::records(db)? {
+            let covering = selection.actual.descriptions
+                .iter()
+                .filter(|d| d.prefix_length <= pool.network_length())
+                .filter(|d| {
+                    match util::id_to_ip_cidr(selection.actual.family, selection.actual.pool, d.prefix_length) {
+                        Ok(cidr) => cidr.contains(&pool.first_address()),
+                        Err(_) => false
+                    }
+                })
+                .map(|d| d.prefix_length)
+                .max();
+
+            if covering.is_some() && covering > deepest.as_ref().and_then(|d| d.selected_prefix_length) {
+                selection.selected_prefix_length = covering;
+                deepest = Some(selection);
+            }
+        }
+
+        Ok(deepest)
+    }
+
+    pub fn node(pool: &IpCidr, db: &dyn Store) -> IpamResult<Option<Selection<Schema>>> {
+        let id = util::string_to_u128_id(pool.first_address().to_string())?;
+
+        match db.fetch(&Family::of(&pool.first_address()).key(id))? {
+            Some(value) => {
+                let mut selection = Schema::new_from_json(String::from_utf8_lossy(&value).to_string())?;
+
+                match selection.actual.descriptions.iter().any(|d| d.prefix_length == pool.network_length()) {
+                    true => {
+                        selection.selected_prefix_length = Some(pool.network_length());
+                        Ok(Some(selection))
+                    }
+                    false => Ok(None)
+                }
+            }
+            None => Ok(None)
+        }
+    }
+
+    pub fn parent_of(pool: &IpCidr, db: &dyn Store) -> IpamResult<Option<Selection<Schema>>> {
+        let node = match Schema::node(pool, db)? {
+            Some(node) => node,
+            None => return Err(IpamError::NoSuchPool(format!("{:#}", pool)))
+        };
+
+        let shallower = node.actual.descriptions
+            .iter()
+            .filter(|d| d.prefix_length < pool.network_length())
+            .map(|d| d.prefix_length)
+            .max();
+
+        if let Some(prefix_length) = shallower {
+            return Schema::node(&util::id_to_ip_cidr(node.actual.family, node.actual.pool, prefix_length)?, db);
+        }
+
+        match node.actual.parent {
+            Some(parent) => {
+                match db.fetch(&node.actual.family.key(parent))? {
+                    Some(value) => {
+                        let mut selection = Schema::new_from_json(String::from_utf8_lossy(&value).to_string())?;
+                        selection.selected_prefix_length = selection.actual.descriptions
+                            .iter()
+                            .find(|d| d.allocation_prefix_length == pool.network_length())
+                            .map(|d| d.prefix_length);
+                        Ok(Some(selection))
+                    }
+                    None => Err(IpamError::Storage(format!("parent of {:#} is missing", pool)))
+                }
+            }
+            None => Ok(None)
+        }
+    }
+
+    pub fn ancestors(pool: &IpCidr, db: &dyn Store) -> IpamResult<Vec<Selection<Schema>>> {
+        let mut ret: Vec<Selection<Schema>> = Vec::new();
+        let mut current = *pool;
+
+        while let Some(parent) = Schema::parent_of(&current, db)? {
+            current = parent.pool()?;
+            ret.push(parent);
+        }
+
+        Ok(ret)
+    }
+
+    pub fn children_of(pool: &IpCidr, db: &dyn Store) -> IpamResult<Vec<Selection<Schema>>> {
+        let node = match Schema::node(pool, db)? {
+            Some(node) => node,
+            None => return Err(IpamError::NoSuchPool(format!("{:#}", pool)))
+        };
+        let mut ret: Vec<Selection<Schema>> = Vec::new();
+
+        match node.selected_description() {
+            Some(description) if description.allocation_prefix_length > description.prefix_length => {
+                for child in ProtoScope::new_type_backed_proto_scope(*pool)?.children(description.allocation_prefix_length) {
+                    if let Some(cidr) = child.cidr {
+                        if let Some(selection) = Schema::node(&cidr, db)? {
+                            ret.push(selection);
+                        }
+                    }
+                }
+            }
+            _ => ()
+        }
+
+        Ok(ret)
+    }
+}
+
+impl Selection<Schema> {
+    pub fn selected_description(&self) -> Option<&SchemaDescription> {
+        let prefix_length = self.selected_prefix_length?;
+
+        self.actual.descriptions
+            .iter()
+            .find(|d| d.prefix_length == prefix_length)
+    }
+
+    pub fn selected_description_mut(&mut self) -> Option<&mut SchemaDescription> {
+        let prefix_length = self.selected_prefix_length?;
+
+        self.actual.descriptions
+            .iter_mut()
+            .find(|d| d.prefix_length == prefix_length)
+    }
+
+    pub fn pool(&self) -> IpamResult<IpCidr> {
+        match self.selected_prefix_length {
+            Some(prefix_length) => util::id_to_ip_cidr(self.actual.family, self.actual.pool, prefix_length),
+            None => Err(IpamError::InvalidRequest("no prefix length selected".to_string()))
+        }
+    }
+}
+
 impl locking_operations for Selection<Schema> {
-    fn lock(&self) -> Result<bool, Box<dyn Error>> {
-        todo!()
+    fn lock(&mut self, lock: Lock) -> IpamResult<bool> {
+        let pool = self.pool()?;
+
+        match self.selected_description_mut() {
+            Some(description) => description.lock(lock),
+            None => Err(IpamError::NoSuchPool(format!("{:#}", pool)))
+        }
     }
 
-    fn unlock(&self) -> Result<bool, Box<dyn Error>> {
-        todo!()
+    fn unlock(&mut self) -> IpamResult<bool> {
+        let pool = self.pool()?;
+
+        match self.selected_description_mut() {
+            Some(description) => description.unlock(),
+            None => Err(IpamError::NoSuchPool(format!("{:#}", pool)))
+        }
     }
 
-    fn is_locked(&self) -> Result<bool, Box<dyn Error>> {
-        todo!()
+    fn is_locked(&self) -> IpamResult<bool> {
+        match self.selected_description() {
+            Some(description) => description.is_locked(),
+            None => Err(IpamError::NoSuchPool(format!("{:#}", self.pool()?)))
+        }
     }
 }
 
 impl locking_operations for SchemaDescription {
-    fn lock(&self) -> Result<bool, Box<dyn Error>> {        
-        todo!()
+    fn lock(&mut self, lock: Lock) -> IpamResult<bool> {
+        let changed = !self.locked;
+        self.locked = true;
+        self.lock = Some(lock);
+        Ok(changed)
     }
 
-    fn unlock(&self) -> Result<bool, Box<dyn Error>> {        
-        todo!()
+    fn unlock(&mut self) -> IpamResult<bool> {
+        let changed = self.locked;
+        self.locked = false;
+        self.lock = None;
+        Ok(changed)
     }
 
-    fn is_locked(&self) -> Result<bool, Box<dyn Error>> {
+    fn is_locked(&self) -> IpamResult<bool> {
         Ok(self.locked)
     }
 }
@@ -308,126 +525,229 @@ mod interpolation_tests {
 #[cfg(test)]
 mod data_store_tests {
     use crate::schema::*;
-    #[test]
-    fn test_schema_dao_with_env() {
-        std::env::set_var("SCHEMA_DB_FILE", "");   
-        let result = Schema::dao();
-
-        assert_eq!(result.is_ok(), true)
-    }
-
+    use crate::storage::{MemoryStore, Transaction};
     #[test]
     fn db_is_not_initialized() {
-        std::env::set_var("SCHEMA_DB_FILE", "");     
-        let mut dao = Schema::dao().unwrap();
+        let mut dao = MemoryStore::new();
 
         assert_eq!(Schema::is_db_initialized(&mut dao).unwrap(), false);        
     }
 
      #[test]
     fn db_is_initialized() {
-        std::env::set_var("SCHEMA_DB_FILE", "");     
-        let mut dao = Schema::dao().unwrap();
-        assert_eq!(initialize_schema_db_steps(&mut dao), true);
-        assert_eq!(Schema::is_db_initialized(&mut dao).is_ok(), true);
+        let mut dao = MemoryStore::new();
+        assert_eq!(initialize_schema_db_steps(&mut MemoryStore::new(), &mut dao), true);
+        assert_eq!(Schema::is_db_initialized(&mut dao).unwrap(), true);
     }
 
     #[test]
-    fn can_create_initial_scopes() {
-        let tmp_file = tempfile::NamedTempFile::new().unwrap().into_temp_path();
-        let tmp = tmp_file.to_str().unwrap();
-        std::env::set_var("SCHEMA_DB_FILE", tmp);     
-        std::env::set_var("SCOPE_DB_FILE", tmp);     
+    fn initialized_schema_covers_root() {
+        let mut dao = MemoryStore::new();
+        Schema::initialize_db(&mut dao).unwrap();
+
+        let root = Schema::containing(&IpCidr::from_str("100.64.0.0/20").unwrap(), &dao).unwrap().unwrap();
+        assert_eq!(root.actual.parent, None);
+        assert_eq!(Schema::exists_in_database(Family::V4, root.actual.pool, &dao).unwrap(), true);
+        assert_eq!(Schema::exists_in_database(Family::V6, root.actual.pool, &dao).unwrap(), false);
+        assert_eq!(root.actual.descriptions[1].prefix_length, 20);
+        assert_eq!(Schema::containing(&IpCidr::from_str("100.64.112.0/24").unwrap(), &dao).unwrap().is_some(), true);
+        assert_eq!(Schema::containing(&IpCidr::from_str("100.64.128.0/24").unwrap(), &dao).unwrap().is_none(), true);
+    }
+
+    #[test]
+    fn seed_from_config() {
+        let mut dao = MemoryStore::new();
+        let config = SchemaConfig::from_toml(r#"
+            [[root]]
+            network = "10.10.0.0/16"
+            prefix_length = 24
+            tags = ["team-a"]
+
+            [[root]]
+            network = "10.20.0.0/22"
+            prefix_length = 24
+            locked = true
+        "#).unwrap();
+        Schema::seed(&mut dao, &config).unwrap();
+
+        assert_eq!(Schema::records(&dao).unwrap().len(), 256 + 4);
+
+        let child = util::string_to_u128_id("10.10.7.0".to_string()).unwrap();
+        let team = Schema::records(&dao).unwrap().into_iter().find(|s| s.actual.pool == child).unwrap();
+        assert_eq!(team.actual.parent, Some(util::string_to_u128_id("10.10.0.0".to_string()).unwrap()));
+        assert_eq!(team.actual.descriptions[0].tags, vec!["team-a"]);
+
+        let locked = Schema::containing(&IpCidr::from_str("10.20.3.0/24").unwrap(), &dao).unwrap().unwrap();
+        assert_eq!(locked.actual.descriptions[0].locked, true);
+        assert_eq!(Schema::containing(&IpCidr::from_str("100.64.0.0/20").unwrap(), &dao).unwrap().is_none(), true);
+    }
+
+    #[test]
+    fn seed_from_default_address_pools() {
+        let mut dao = MemoryStore::new();
+        let config = SchemaConfig::from_default_address_pools(r#"[{"base":"172.80.0.0/16","size":24}]"#).unwrap();
+        Schema::seed(&mut dao, &config).unwrap();
+
+        let root = Schema::containing(&IpCidr::from_str("172.80.0.0/24").unwrap(), &dao).unwrap().unwrap();
+        assert_eq!(root.actual.descriptions[0].prefix_length, 16);
+        assert_eq!(root.actual.descriptions[1].prefix_length, 24);
+        assert_eq!(Schema::records(&dao).unwrap().len(), 256);
+    }
+
+    #[test]
+    fn seed_nested_levels() {
+        let mut dao = MemoryStore::new();
+        let config = SchemaConfig::from_toml(r#"
+            [[root]]
+            network = "10.0.0.0/14"
+
+            [[root.level]]
+            prefix_length = 16
+
+            [[root.level]]
+            prefix_length = 20
+            locked = true
+
+            [[root.level]]
+            prefix_length = 24
+        "#).unwrap();
+        Schema::seed(&mut dao, &config).unwrap();
+
+        assert_eq!(Schema::records(&dao).unwrap().len(), 1024);
+
+        let root = Schema::node(&IpCidr::from_str("10.0.0.0/14").unwrap(), &dao).unwrap().unwrap();
+        let prefix_lengths: Vec<u8> = root.actual.descriptions.iter().map(|d| d.prefix_length).collect();
+        assert_eq!(prefix_lengths, vec![14, 16, 20, 24]);
+        assert_eq!(root.selected_description().unwrap().allocation_prefix_length, 16);
+
+        let network = IpCidr::from_str("10.1.32.0/24").unwrap();
+        let ancestors: Vec<String> = Schema::ancestors(&network, &dao).unwrap()
+            .iter()
+            .map(|s| format!("{:#}", s.pool().unwrap()))
+            .collect();
+        assert_eq!(ancestors, vec!["10.1.32.0/20", "10.1.0.0/16", "10.0.0.0/14"]);
+        assert_eq!(Schema::node(&network, &dao).unwrap().unwrap().selected_description().unwrap().locked, true);
+        assert_eq!(Schema::node(&IpCidr::from_str("10.1.0.0/16").unwrap(), &dao).unwrap().unwrap().selected_description().unwrap().locked, false);
+
+        let sites = Schema::children_of(&IpCidr::from_str("10.0.0.0/14").unwrap(), &dao).unwrap();
+        let hosts = Schema::children_of(&IpCidr::from_str("10.1.0.0/16").unwrap(), &dao).unwrap();
+        assert_eq!(sites.len(), 4);
+        assert_eq!(hosts.len(), 16);
+        assert_eq!(format!("{:#}", hosts[2].pool().unwrap()), "10.1.32.0/20");
+        assert_eq!(Schema::children_of(&network, &dao).unwrap().len(), 0);
+        assert_eq!(Schema::containing(&IpCidr::from_str("10.1.33.0/24").unwrap(), &dao).unwrap().unwrap().pool().unwrap(), IpCidr::from_str("10.1.33.0/24").unwrap());
+    }
+
+    #[test]
+    fn reads_two_slot_descriptions() {
+        let schema = Schema::new_from_json(
+            r#"{"pool":1681915904,"family":"V4","descriptions":[{"prefix_length":17,"allocation_prefix_length":17,"locked":false},null],"parent":null}"#.to_string()).unwrap();
+
+        assert_eq!(schema.actual.descriptions.len(), 1);
+        assert_eq!(schema.actual.descriptions[0].prefix_length, 17);
+    }
+
+    #[test]
+    fn seed_generated_ula_root() {
+        let mut dao = MemoryStore::new();
+        let config = SchemaConfig::from_toml(r#"
+            [[root]]
+            network = "ula"
+            prefix_length = 52
+        "#).unwrap();
+        Schema::seed(&mut dao, &config).unwrap();
+
+        let records = Schema::records(&dao).unwrap();
+        let root = records.iter().find(|s| s.actual.parent.is_none()).unwrap();
+        let prefix = util::id_to_ip_cidr(root.actual.family, root.actual.pool, 48).unwrap();
+
+        assert_eq!(records.len(), 16);
+        assert_eq!(prefix.is_ipv6(), true);
+        assert_eq!(format!("{}", prefix.first_address()).starts_with("fd"), true);
+        assert_eq!(root.actual.descriptions[1].prefix_length, 52);
+    }
+
+    #[test]
+    fn seed_rejects_bad_roots() {
+        let overlapping = SchemaConfig::from_toml(r#"
+            [[root]]
+            network = "10.10.0.0/16"
+            prefix_length = 24
+
+            [[root]]
+            network = "10.10.128.0/17"
+            prefix_length = 24
+        "#).unwrap();
+        let too_short = SchemaConfig::from_toml(r#"
+            [[root]]
+            network = "10.10.0.0/16"
+            prefix_length = 16
+        "#).unwrap();
+
+        assert_eq!(Schema::seed(&mut MemoryStore::new(), &overlapping).is_err(), true);
+        let too_many = SchemaConfig::from_toml(r#"
+            [[root]]
+            network = "fd00:1::/32"
+            prefix_length = 64
+        "#).unwrap();
+
+        assert_eq!(Schema::seed(&mut MemoryStore::new(), &too_short).is_err(), true);
+        assert_eq!(Schema::seed(&mut MemoryStore::new(), &too_many).is_err(), true);
+    }
 
-        let mut schema_dao = Schema::dao().unwrap();
-        let mut scope_dao = Scope::dao().unwrap();
+    #[test]
+    fn can_create_initial_scopes() {
+        let mut schema_dao = MemoryStore::new();
+        let mut scope_dao = MemoryStore::new();
         
-        assert_eq!(initialize_schema_db_steps(&mut schema_dao), true);
-        assert_eq!(initialize_scope_db_steps(&mut scope_dao), true)
+        assert_eq!(initialize_schema_db_steps(&mut scope_dao, &mut schema_dao), true);
+        assert_eq!(util::create_initial_scopes(&mut scope_dao, &mut schema_dao).is_ok(), true);
+        assert_eq!(Scope::records(&scope_dao).unwrap().len(), Schema::records(&schema_dao).unwrap().len());
     }
 
     #[test]
     fn test_roll_back_tx() {
-        std::env::set_var("SCHEMA_DB_FILE", "");
-        let mut dao = Schema::dao().unwrap();
+        let mut dao = MemoryStore::new();
+        let mut scope_dao = MemoryStore::new();
 
-        match Schema::begin_tx(&mut dao) {
-            Ok(_) => {
-                match Schema::initialize_db(&mut dao) {
-                    Ok(_) => {
-                        match Schema::roll_back_tx(&mut dao) {
-                            Ok(_) => (),
-                            Err(_) => panic!("rollback of init failed"),
-                        }
-                    }
-                    Err(_) => panic!("failed to initialize db"),
-                }
-            }
-            Err(_) => panic!("failed to begin tx"),
+        {
+            let mut tx = Transaction::begin(&mut scope_dao, &mut dao).unwrap();
+            Schema::initialize_db(tx.stores().1).unwrap();
         }
+
+        assert_eq!(Schema::is_db_initialized(&mut dao).unwrap(), false);
     }
 
     #[test]
-    fn test_begin_tx_within_tx() {        
-        std::env::set_var("SCHEMA_DB_FILE", "");
-        let mut dao = Schema::dao().unwrap();
+    fn test_begin_tx_within_tx() {
+        let mut dao = MemoryStore::new();
+        let mut scope_dao = MemoryStore::new();
+        let mut tx = Transaction::begin(&mut scope_dao, &mut dao).unwrap();
 
-        match Schema::begin_tx(&mut dao) {
-            Ok(_) => {
-                match Schema::begin_tx(&mut dao) {
-                    Ok(_) => todo!("is this bad or good"),
-                    Err(_) => panic!("unknown behavior")
-                }
-            }
-            Err(e) => panic!("failed to begin transaction: {} ", e)
-        }
+        assert_eq!(tx.stores().1.begin().is_err(), true);
     }
 
     #[test]
-    fn test_commit_no_tx() {      
-        std::env::set_var("SCHEMA_DB_FILE", "");  
-        let mut dao = Schema::dao().unwrap();
+    fn test_commit_no_tx() {
+        let mut dao = MemoryStore::new();
 
-        match Schema::commit(&mut dao) {
-            Ok(_) => todo!("end tx suceeeded but no transaction existed"),
-            Err(_) => (),
-        }
+        assert_eq!(dao.commit().is_err(), true);
     }
 
-    fn initialize_scope_db_steps(scope_dao: &mut UnQLite) -> bool {
-        match Scope::begin_tx(scope_dao) {
-            Ok(_) => {
-                match Scope::initialize_db(scope_dao) {
-                    Ok(_) => {
-                        match Scope::commit(scope_dao) {
-                            Ok(_) => return true,
-                            Err(_) => todo!(),
-                        }
-                    }
-                    Err(_) => todo!(),
-                }
-            }
-            Err(_) => todo!(),
-        }
-    }
+    fn initialize_schema_db_steps(scope_dao: &mut dyn Store, dao: &mut dyn Store) -> bool {
+        let mut tx = match Transaction::begin(scope_dao, dao) {
+            Ok(tx) => tx,
+            Err(e) => panic!("failed to begin transaction: {} ", e)
+        };
 
-    fn initialize_schema_db_steps(dao: &mut UnQLite) -> bool {         
-        match Schema::begin_tx(dao) {
+        match Schema::initialize_db(tx.stores().1) {
             Ok(_) => {
-                match Schema::initialize_db(dao) {
-                    Ok(_) => {
-                        match Schema::commit(dao) {
-                            Ok(_) => match Schema::is_db_initialized(dao) {
-                                Ok(_) => return true,
-                                Err(e) => panic!("db doesn't appear to be initialized after initialization: {}", e)
-                            }
-                            Err(e) => panic!("failed to commit: {}", e)
-                        }
-                    },
-                    Err(e) => panic!("initialization failed: {}", e)
+                match tx.commit() {
+                    Ok(_) => true,
+                    Err(e) => panic!("failed to commit: {}", e)
                 }
-            },
-            Err(e) => panic!("failed to begin transaction: {} ", e)
+            }
+            Err(e) => panic!("initialization failed: {}", e)
         }
     }
-}
\ No newline at end of file
+}
@@ -1,106 +1,214 @@
-use std::error::Error;
-use std::time::SystemTime;
-use cidr::IpCidr;
-use unqlite::Cursor;
-use unqlite::Transaction;
-use unqlite::UnQLite;
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use cidr::{IpCidr, IpInet};
 use crate::model::*;
+use crate::error::*;
 use crate::interpolate::*;
+use crate::interpolate::factory as faktory;
 use crate::schema::*;
+use crate::storage::{open_store, Store};
+use crate::util;
+
+// --ipam-opt strategy=... picks how a network's pool and addresses are placed, and
+// --ipam-opt strategy-key=... is what a hashed strategy hashes to place the pool, first-fit without one
+pub(crate) const STRATEGY_OPTION: &str = "strategy";
+pub(crate) const STRATEGY_KEY_OPTION: &str = "strategy-key";
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AddressKind {
+    Gateway,
+    Endpoint
+}
+
+// what an allocation was requested with, kept so a replayed request can be told from a new one
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RequestIdentity {
+    pub options: BTreeMap<String, String>
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct AddressDescription {
+    pub address: String,
+    pub kind: AddressKind,
+    #[serde(default)]
+    pub request: Option<RequestIdentity>,
+    #[serde(default)]
+    pub expires: Option<SystemTime>
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct MacBinding {
+    pub mac: String,
+    pub address: String,
+    pub last_used: SystemTime
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct ReleasedAddress {
+    pub address: String,
+    pub at: SystemTime
+}
+
+// when a released address may be handed out again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReusePolicy {
+    Immediate,
+    // released addresses wait out the cooldown unless nothing else is free
+    Cooldown(Duration),
+    // released addresses come back only once nothing else is free
+    LeastRecentlyUsed
+}
+
+impl Default for ReusePolicy {
+    fn default() -> Self {
+        ReusePolicy::Immediate
+    }
+}
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct ScopeDescription {
-    prefix_length: u8,
-    locked: bool,
-    allocated: bool,
-    tags: Vec<String>
+    pub prefix_length: u8,
+    pub locked: bool,
+    pub allocated: bool,
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub addresses: Vec<AddressDescription>,
+    #[serde(default)]
+    pub sub_pool: Option<String>,
+    #[serde(default)]
+    pub lock: Option<Lock>,
+    #[serde(default)]
+    pub request: Option<RequestIdentity>,
+    #[serde(default)]
+    pub mac_bindings: Vec<MacBinding>,
+    #[serde(default)]
+    pub released: Vec<ReleasedAddress>,
+    #[serde(default)]
+    pub strategy: Option<Strategy>,
+    // made up by claim_pool for a prefix the schema doesn't have, and dropped again on release
+    #[serde(default)]
+    pub claimed: bool
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Scope {
     pub id: u128,
+    pub family: Family,
     pub parent: Option<u128>,
     pub modified: SystemTime,
     pub created: SystemTime,
     pub descriptions: Vec<ScopeDescription>,
+    #[serde(default)]
+    pub revision: u64
 }
 
 impl data_operations<Scope, ScopeDescription> for Scope {
-    fn begin_tx(db: &mut UnQLite) -> Result<(), unqlite::Error> {
-        match db.begin() {
-            Ok(_) => {
-                Ok(())
-            }
-            Err(_) => {
-                todo!("begin tx err")
-            }
-        }
+    // scopes are built from the schema with util::create_initial_scopes, which needs both stores
+    fn initialize_db(db: &mut dyn Store) -> IpamResult<()> {
+        util::migrate_record_keys(db)?;
+        Ok(())
     }
 
-    fn roll_back_tx(db: &mut UnQLite) -> Result<(), unqlite::Error> {
-        match db.rollback() {
-            Ok(_) => {
-                todo!()
-            }
-            Err(_) => {
-                todo!()
-            }
+    fn dao() -> IpamResult<Box<dyn Store>> {
+        match std::env::var("SCOPE_DB_FILE") {
+            Ok(path) => open_store(&path),
+            Err(e) => Err(IpamError::Storage(format!("SCOPE_DB_FILE: {}", e)))
         }
     }
 
-    fn commit(db: &mut UnQLite) -> Result<(), unqlite::Error> {
-        match db.commit() {
-            Ok(_) => {                
+    fn save(s: &mut Selection<Scope>, db: &mut dyn Store) -> IpamResult<()> {
+        s.saved = true;
+
+        match db.store(
+            &s.actual.family.key(s.actual.id),
+            serde_json::to_string(&mut s.actual)?.as_bytes()) {
+            Ok(_) => {
                 Ok(())
             }
-            Err(e) => Err(e)
+            Err(e) => {
+                Err(IpamError::Storage(format!("failed to save scope {}: {}", s.actual.id, e)))
+            }
         }
     }
 
-    fn initialize_db(_db: &mut UnQLite) -> Result<(), Box<dyn Error>> {
-        let mut scope_dao = Scope::dao().unwrap();
-        let mut schema_dao = Schema::dao().unwrap();
-        crate::util::create_initial_scopes(&mut scope_dao, &mut schema_dao)
+    fn exists_in_database(family: Family, id: u128, db: &dyn Store) -> IpamResult<bool> {
+        db.contains(&family.key(id))
     }
 
-    fn dao() -> Result<UnQLite, Box<dyn Error>> {
-        Ok(UnQLite::create(std::env::var("SCOPE_DB_FILE")?))
+    fn retrieve_all(db: &dyn Store) -> IpamResult<Vec<Selection<Scope>>> {
+        Scope::records(db)
     }
 
-    fn save(_s: &mut Selection<Scope>, _db: &mut UnQLite) -> Result<(), Box<dyn Error>> {
-        todo!()
+    fn allocate_pool(tags: Vec<String>, v6: bool, db: &mut dyn Store) -> IpamResult<Selection<Scope>> {
+        Scope::allocate_tagged_pool(&tags, v6, db)
     }
 
-    fn exists_in_database(_id: u128) -> Result<bool, Box<dyn Error>> {
-        todo!()
+    fn allocate_address(pool_id: String, address: Option<String>, db: &mut dyn Store) -> IpamResult<IpInet> {
+        Scope::reserve_address(&pool_id, address, AddressKind::Endpoint, SystemTime::now(), db)
     }
 
-    fn retrieve_all() -> Result<Vec<Selection<Scope>>, Box<dyn Error>> {
-        panic!("Not implemented for Scopes")
-    }
+    fn release_pool(pool_id: String, db: &mut dyn Store) -> IpamResult<()> {
+        let mut selection = Scope::select(&pool_id, db)?;
+        let prefix_length = selection.selected_prefix_length;
 
-    fn allocate_pool(_tags: Vec<String>) -> Result<Selection<Scope>, Box<dyn Error>> {
-        todo!()
-    }
+        match selection.selected_description() {
+            // left behind, a claimed /24 would be handed out ahead of the schema's pools
+            Some(description) if description.claimed => {
+                selection.actual.descriptions.retain(|d| Some(d.prefix_length) != prefix_length);
+            }
+            Some(description) => {
+                description.allocated = false;
+                description.addresses.clear();
+                description.request = None;
+                description.mac_bindings.clear();
+                description.released.clear();
+                description.sub_pool = None;
+            }
+            None => {
+                return Err(IpamError::NoSuchPool(pool_id))
+            }
+        }
 
-    fn allocate_address(_network: String) -> Result<Selection<Scope>, Box<dyn Error>> {
-        todo!()
-    }
+        if selection.actual.descriptions.is_empty() {
+            return db.delete(&selection.actual.family.key(selection.actual.id));
+        }
 
-    fn release_pool(_network: String) -> Result<(), Box<dyn Error>> {
-        todo!()
+        selection.actual.modified = SystemTime::now();
+        Scope::compare_and_save(&mut selection, db)
     }
 
-    fn release_address(_network: String) -> Result<(), Box<dyn Error>> {
-        todo!()
-    }
+    fn release_address(pool_id: String, address: String, now: SystemTime, db: &mut dyn Store) -> IpamResult<()> {
+        let mut selection = Scope::select(&pool_id, db)?;
+        let address = util::string_to_ip_addr(address)?.to_string();
 
-    fn is_db_initialized(db: &mut UnQLite) -> Result<bool, Box<dyn Error>> {
-        match db.first() {
+        match selection.selected_description() {
+            Some(description) => {
+                match description.addresses.iter().any(|a| a.address == address) {
+                    true => {
+                        description.free(&address, now);
+                    }
+                    false => {
+                        return Err(IpamError::InvalidRequest(format!("{} is not allocated in {}", address, pool_id)))
+                    }
+                }
+            }
             None => {
+                return Err(IpamError::NoSuchPool(pool_id))
+            }
+        }
+
+        selection.actual.modified = now;
+        Scope::compare_and_save(&mut selection, db)
+    }
+
+    fn is_db_initialized(db: &mut dyn Store) -> IpamResult<bool> {
+        match db.is_empty()? {
+            true => {
                 Ok(false)
             }
-            Some(_) => {
+            false => {
                 Ok(true)
             }
         }
@@ -108,26 +216,37 @@ impl data_operations<Scope, ScopeDescription> for Scope {
 }
 
 impl crate::model::factory<Scope, ScopeDescription, Schema, SchemaDescription> for Scope {
-    fn new_from_string(_network: String, _prefix_length: u8, _parent: Option<&mut Selection<Scope>>) -> Result<Selection<Scope>, Box<dyn Error>> {
+    fn new_from_string(_network: String, _prefix_length: u8, _parent: Option<&mut Selection<Scope>>) -> IpamResult<Selection<Scope>> {
         todo!()
     }
 
-    fn new_from_bytes(_network: Vec<u8>, _prefix_length: u8, _parent: Option<&mut Selection<Scope>>) -> Result<Selection<Scope>, Box<dyn Error>> {
+    fn new_from_bytes(_network: Vec<u8>, _prefix_length: u8, _parent: Option<&mut Selection<Scope>>) -> IpamResult<Selection<Scope>> {
         todo!()
     }
 
-    fn new_from_proto_scope(network: ProtoScope<IpCidr>, parent: Option<&mut Selection<Scope>>) -> Result<Selection<Scope>, Box<dyn Error>> {
+    fn new_from_proto_scope(network: ProtoScope<IpCidr>, parent: Option<&mut Selection<Scope>>) -> IpamResult<Selection<Scope>> {
         network.scope_from_proto_scope(parent)
     }
 
-    fn new_from_selection(_network: Selection<Schema>) -> Result<Selection<Scope>, Box<dyn Error>> {
+    fn new_from_selection(_network: Selection<Schema>) -> IpamResult<Selection<Scope>> {
         Ok(Selection {
             actual: Scope {
                 id: _network.actual.pool,
+                family: _network.actual.family,
                 parent: _network.actual.parent,
                 modified: SystemTime::now(),
                 created: SystemTime::now(),
-                descriptions: Vec::new()
+                descriptions: _network.actual.descriptions
+                    .iter()
+                    .map(|d| ScopeDescription {
+                        locked: d.locked,
+                        tags: d.tags.clone(),
+                        lock: d.lock.clone(),
+                        strategy: d.strategy,
+                        ..ScopeDescription::new(d.prefix_length)
+                    })
+                    .collect(),
+                revision: 0
             },
             selected_prefix_length: _network.selected_prefix_length,
             saved: false,
@@ -135,71 +254,1287 @@ impl crate::model::factory<Scope, ScopeDescription, Schema, SchemaDescription> f
         })
     }
 
-    fn new_from_json(_json: String) -> Result<Selection<Scope>, Box<dyn Error>> {
-        todo!()
+    fn new_from_json(mut json: String) -> IpamResult<Selection<Scope>> {
+        Ok(Selection {
+            actual: serde_json::from_str(&mut json)?,
+            selected_prefix_length: Option::None,
+            saved: false,
+            operation: SelectionOperation::DEFAULT,
+        })
     }
 
-    fn to_proto_scope(&self) -> Result<ProtoScope<IpCidr>, Box<dyn Error>> {
-        todo!()
+    fn to_proto_scope(&self) -> IpamResult<ProtoScope<IpCidr>> {
+        match self.descriptions.first() {
+            Some(description) => self.proto_scope_with_prefix_length(description.prefix_length),
+            None => Err(IpamError::Storage(format!("scope {} has no descriptions", self.id)))
+        }
     }
 
-    fn new_selection(&self) -> Result<Selection<Scope>, Box<dyn Error>> {
+    fn new_selection(&self) -> IpamResult<Selection<Scope>> {
         todo!()
     }
 }
 
+impl Scope {
+    // the stored record must still be at the revision the selection was read at, so an update
+    // computed from a stale read can never overwrite a newer one
+    pub fn compare_and_save(s: &mut Selection<Scope>, db: &mut dyn Store) -> IpamResult<()> {
+        let stored = match db.fetch(&s.actual.family.key(s.actual.id))? {
+            Some(value) => serde_json::from_slice::<Scope>(&value)?.revision,
+            None => 0
+        };
+
+        if stored != s.actual.revision {
+            return Err(IpamError::StaleRecord(s.pool_id().unwrap_or(s.actual.id.to_string())));
+        }
+
+        s.actual.revision += 1;
+        Scope::save(s, db)
+    }
+
+    pub fn records(db: &dyn Store) -> IpamResult<Vec<Selection<Scope>>> {
+        let mut ret: Vec<Selection<Scope>> = Vec::new();
+
+        for (_key, value) in db.entries()? {
+            ret.push(Scope::new_from_json(String::from_utf8_lossy(&value).to_string())?);
+        }
+
+        Ok(ret)
+    }
+
+    pub fn claim_pool(pool: IpCidr, sub_pool: Option<IpCidr>, db: &mut dyn Store, schema_db: &dyn Store) -> IpamResult<Selection<Scope>> {
+        let schema = match Schema::containing(&pool, schema_db)? {
+            Some(schema) => schema,
+            None => {
+                return Err(IpamError::InvalidRequest(format!("{:#} is not within any configured schema", pool)))
+            }
+        };
+
+        if let Some(sub_pool) = sub_pool {
+            if !pool.contains(&sub_pool.first_address()) || !pool.contains(&sub_pool.last_address()) {
+                return Err(IpamError::InvalidRequest(format!("sub pool {:#} is not within {:#}", sub_pool, pool)));
+            }
+        }
+
+        let records = Scope::records(db)?;
+
+        if Scope::allocated_pools(&records)?.iter().any(|allocated| util::overlaps(allocated, &pool)) {
+            return Err(IpamError::PoolOverlap(format!("{:#}", pool)));
+        }
+
+        if let Some(locked) = Scope::locked_pools(&records)?.iter().find(|(locked, _)| util::overlaps(locked, &pool)) {
+            return Err(Scope::locked_error(locked));
+        }
+
+        let id = util::string_to_u128_id(pool.first_address().to_string())?;
+        let mut selection = match db.fetch(&Family::of(&pool.first_address()).key(id))? {
+            Some(value) => {
+                Scope::new_from_json(String::from_utf8_lossy(&value).to_string())?
+            }
+            None => {
+                let mut selection = Scope::new_from_proto_scope(ProtoScope::new_type_backed_proto_scope(pool)?, None)?;
+                selection.actual.parent = Some(schema.actual.pool).filter(|parent| *parent != id);
+                selection
+            }
+        };
+
+        selection.selected_prefix_length = Some(pool.network_length());
+
+        match selection.selected_description() {
+            Some(description) => {
+                description.allocated = true;
+                description.sub_pool = sub_pool.map(|s| format!("{:#}", s));
+            }
+            None => {
+                selection.actual.descriptions.push(ScopeDescription {
+                    allocated: true,
+                    tags: schema.selected_description().map_or(Vec::new(), |d| d.tags.clone()),
+                    sub_pool: sub_pool.map(|s| format!("{:#}", s)),
+                    strategy: schema.selected_description().and_then(|d| d.strategy),
+                    claimed: true,
+                    ..ScopeDescription::new(pool.network_length())
+                });
+            }
+        }
+
+        selection.actual.modified = SystemTime::now();
+        Scope::compare_and_save(&mut selection, db)?;
+        Ok(selection)
+    }
+
+    pub fn select(pool_id: &String, db: &mut dyn Store) -> IpamResult<Selection<Scope>> {
+        let pool = IpCidr::from_str(pool_id.as_str())?;
+        let id = util::string_to_u128_id(pool.first_address().to_string())?;
+
+        match db.fetch(&Family::of(&pool.first_address()).key(id))? {
+            Some(value) => {
+                let v = String::from_utf8_lossy(&value).to_string();
+                let mut selection = Scope::new_from_json(v)?;
+
+                match selection.actual.descriptions
+                    .iter()
+                    .any(|d| d.prefix_length == pool.network_length()) {
+                    true => {
+                        selection.selected_prefix_length = Some(pool.network_length());
+                        Ok(selection)
+                    }
+                    false => Err(IpamError::NoSuchPool(pool_id.clone()))
+                }
+            }
+            None => Err(IpamError::NoSuchPool(pool_id.clone()))
+        }
+    }
+
+    pub fn allocate_tagged_pool(tags: &Vec<String>, v6: bool, db: &mut dyn Store) -> IpamResult<Selection<Scope>> {
+        Scope::allocate_placed_pool(tags, v6, None, None, db)
+    }
+
+    // leaf pools go first. Among the free pools with the longest prefix, the requested strategy
+    // picks one, or failing that the strategy configured for the first of them
+    pub fn allocate_placed_pool(tags: &Vec<String>, v6: bool, strategy: Option<Strategy>, key: Option<&str>, db: &mut dyn Store) -> IpamResult<Selection<Scope>> {
+        let records = Scope::records(db)?;
+        let allocated = Scope::allocated_pools(&records)?;
+        let locked = Scope::locked_pools(&records)?;
+
+        let mut candidates: Vec<(Family, u128, u8, Option<Strategy>)> = records
+            .iter()
+            .flat_map(|r| r.actual.descriptions
+                .iter()
+                .filter(|d| !d.allocated && !d.locked && Scope::tags_match(&d.tags, tags))
+                .map(move |d| (r.actual.family, r.actual.id, d.prefix_length, d.strategy)))
+            .collect();
+
+        candidates.sort_by(|a, b| b.2.cmp(&a.2).then(a.1.cmp(&b.1)));
+
+        let mut free: Vec<(IpCidr, Option<Strategy>)> = Vec::new();
+
+        for (family, id, prefix_length, configured) in candidates {
+            let pool = util::id_to_ip_cidr(family, id, prefix_length)?;
+
+            if pool.is_ipv6() != v6
+                || allocated.iter().any(|a| util::overlaps(a, &pool))
+                || locked.iter().any(|(l, _)| util::overlaps(l, &pool)) {
+                continue;
+            }
+
+            if free.first().map_or(false, |(f, _)| f.network_length() != prefix_length) {
+                break;
+            }
+
+            free.push((pool, configured));
+        }
+
+        if let Some((_, configured)) = free.first() {
+            let strategy = strategy.or(*configured).unwrap_or_default();
+            // RequestPool carries no network id, so a hashed strategy without --ipam-opt strategy-key
+            // places the pool first-fit and only hashes the addresses in it
+            let start = strategy.start(free.len() as u128, key).unwrap_or(0) as usize;
+            let (pool, _) = free[start];
+            let mut selection = Scope::select(&format!("{:#}", pool), db)?;
+
+            match selection.selected_description() {
+                Some(description) => {
+                    description.allocated = true;
+                }
+                None => {
+                    return Err(IpamError::NoSuchPool(format!("{:#}", pool)))
+                }
+            }
+
+            selection.actual.modified = SystemTime::now();
+            Scope::compare_and_save(&mut selection, db)?;
+            return Ok(selection);
+        }
+
+        let family = match v6 {
+            true => "IPv6",
+            false => "IPv4"
+        };
+
+        match tags.is_empty() {
+            true => Err(IpamError::PoolExhausted(format!("untagged {} pools", family))),
+            false => Err(IpamError::PoolExhausted(format!("{} pools tagged {}", family, tags.join(","))))
+        }
+    }
+
+    fn tags_match(available: &Vec<String>, requested: &Vec<String>) -> bool {
+        match requested.is_empty() {
+            true => available.is_empty(),
+            false => requested.iter().all(|t| available.contains(t))
+        }
+    }
+
+    fn allocated_pools(records: &Vec<Selection<Scope>>) -> IpamResult<Vec<IpCidr>> {
+        let mut ret: Vec<IpCidr> = Vec::new();
+
+        for record in records {
+            for description in record.actual.descriptions.iter().filter(|d| d.allocated) {
+                ret.push(util::id_to_ip_cidr(record.actual.family, record.actual.id, description.prefix_length)?);
+            }
+        }
+
+        Ok(ret)
+    }
+
+    pub fn locked_pools(records: &Vec<Selection<Scope>>) -> IpamResult<Vec<(IpCidr, Option<Lock>)>> {
+        let mut ret: Vec<(IpCidr, Option<Lock>)> = Vec::new();
+
+        for record in records {
+            for description in record.actual.descriptions.iter().filter(|d| d.locked) {
+                ret.push((
+                    util::id_to_ip_cidr(record.actual.family, record.actual.id, description.prefix_length)?,
+                    description.lock.clone()));
+            }
+        }
+
+        Ok(ret)
+    }
+
+    fn locked_error(locked: &(IpCidr, Option<Lock>)) -> IpamError {
+        match &locked.1 {
+            Some(lock) => IpamError::SchemaLocked(format!("{:#} (locked by {}: {})", locked.0, lock.by, lock.reason)),
+            None => IpamError::SchemaLocked(format!("{:#}", locked.0))
+        }
+    }
+
+    pub fn reserve_address(pool_id: &String, address: Option<String>, kind: AddressKind, now: SystemTime, db: &mut dyn Store) -> IpamResult<IpInet> {
+        let mut selection = Scope::select(pool_id, db)?;
+        let reserved = Scope::reserve_in(&mut selection, address, kind, db)?;
+
+        selection.actual.modified = now;
+        Scope::compare_and_save(&mut selection, db)?;
+        Ok(reserved)
+    }
+
+    // the pool's own record and its ancestors' hold every lock that can cover it
+    fn covering_lock(scope: &Scope, pool: &IpCidr, db: &dyn Store) -> IpamResult<Option<(IpCidr, Option<Lock>)>> {
+        let mut visited: HashSet<u128> = HashSet::new();
+        let mut ancestor: Option<Scope> = None;
+
+        loop {
+            let record = ancestor.as_ref().unwrap_or(scope);
+
+            for description in record.descriptions.iter().filter(|d| d.locked && d.prefix_length <= pool.network_length()) {
+                let locked = util::id_to_ip_cidr(record.family, record.id, description.prefix_length)?;
+
+                if locked.contains(&pool.first_address()) {
+                    return Ok(Some((locked, description.lock.clone())));
+                }
+            }
+
+            visited.insert(record.id);
+
+            let parent = match record.parent.filter(|p| !visited.contains(p)) {
+                Some(parent) => parent,
+                None => return Ok(None)
+            };
+
+            ancestor = match db.fetch(&record.family.key(parent))? {
+                Some(value) => Some(Scope::new_from_json(String::from_utf8_lossy(&value).to_string())?.actual),
+                None => return Ok(None)
+            };
+        }
+    }
+
+    // reserves on a selection the caller saves, so one read and one write cover a whole allocation
+    fn reserve_in(selection: &mut Selection<Scope>, address: Option<String>, kind: AddressKind, db: &dyn Store) -> IpamResult<IpInet> {
+        let pool = selection.pool()?;
+        let pool_id = selection.pool_id()?;
+
+        // addresses already handed out stay valid, but nothing new comes out of a locked pool
+        if let Some(locked) = Scope::covering_lock(&selection.actual, &pool, db)? {
+            return Err(Scope::locked_error(&locked));
+        }
+
+        let address = match (address, kind) {
+            (Some(address), _) => util::string_to_ip_addr(address)?,
+            (None, AddressKind::Gateway) => util::increment_address(pool.first_address())?,
+            (None, _) => selection.next_free_address()?
+        };
+
+        if !pool.contains(&address) {
+            return Err(IpamError::InvalidRequest(format!("{} is outside of pool {}", address, pool_id)));
+        }
+
+        if !Scope::is_host_address(&pool, &address) {
+            return Err(IpamError::InvalidRequest(format!("{} is not a usable host address in {}", address, pool_id)));
+        }
+
+        match selection.selected_description() {
+            Some(description) => {
+                match description.addresses.iter().find(|a| a.address == address.to_string()) {
+                    Some(reserved) if reserved.kind == kind && kind == AddressKind::Gateway => {
+                        return Ok(IpInet::new(address, pool.network_length())?)
+                    }
+                    Some(_) => {
+                        return Err(IpamError::AddressInUse(address.to_string()))
+                    }
+                    None => {
+                        description.addresses.push(AddressDescription {
+                            address: address.to_string(),
+                            kind: kind,
+                            request: None,
+                            expires: None
+                        });
+                        description.released.retain(|r| r.address != address.to_string());
+                    }
+                }
+            }
+            None => {
+                return Err(IpamError::NoSuchPool(pool_id))
+            }
+        }
+
+        Ok(IpInet::new(address, pool.network_length())?)
+    }
+
+    // Docker re-issues the request of every network and endpoint it knows when it restarts. One
+    // that matches what an allocation was made for gets that allocation back, even from a locked pool
+    pub fn replayed_pool(pool_id: &String, sub_pool: Option<IpCidr>, request: &RequestIdentity, db: &mut dyn Store) -> IpamResult<bool> {
+        let mut selection = match Scope::select(pool_id, db) {
+            Ok(selection) => selection,
+            Err(IpamError::NoSuchPool(_)) => return Ok(false),
+            Err(e) => return Err(e)
+        };
+        let sub_pool = sub_pool.map(|s| format!("{:#}", s));
+
+        match selection.selected_description() {
+            Some(description) => {
+                Ok(description.allocated && description.sub_pool == sub_pool && description.request.as_ref() == Some(request))
+            }
+            None => Ok(false)
+        }
+    }
+
+    pub fn replayed_address(pool_id: &String, address: &String, kind: AddressKind, request: &RequestIdentity, db: &mut dyn Store) -> IpamResult<Option<IpInet>> {
+        let mut selection = Scope::select(pool_id, db)?;
+        let pool = selection.pool()?;
+        let address = util::string_to_ip_addr(address.clone())?;
+
+        match selection.selected_description() {
+            Some(description) if description.addresses
+                .iter()
+                .any(|a| a.address == address.to_string() && a.kind == kind && a.request.as_ref() == Some(request)) => {
+                Ok(Some(IpInet::new(address, pool.network_length())?))
+            }
+            _ => Ok(None)
+        }
+    }
+
+    // records the request behind a pool, or behind one of its addresses
+    pub fn remember_request(pool_id: &String, address: Option<IpAddr>, request: RequestIdentity, now: SystemTime, db: &mut dyn Store) -> IpamResult<()> {
+        let mut selection = Scope::select(pool_id, db)?;
+
+        match (selection.selected_description(), address) {
+            (Some(description), None) => {
+                description.request = Some(request);
+            }
+            (Some(description), Some(address)) => {
+                match description.addresses.iter_mut().find(|a| a.address == address.to_string()) {
+                    Some(reserved) => {
+                        reserved.request = Some(request);
+                    }
+                    None => {
+                        return Err(IpamError::InvalidRequest(format!("{} is not allocated in {}", address, pool_id)))
+                    }
+                }
+            }
+            (None, _) => {
+                return Err(IpamError::NoSuchPool(pool_id.clone()))
+            }
+        }
+
+        selection.actual.modified = now;
+        Scope::compare_and_save(&mut selection, db)
+    }
+
+    // picks the address for an endpoint that asked for none. With sticky MACs on, an endpoint gets
+    // back the address its MAC had last unless that was released more than idle ago or was taken
+    // since, and free addresses still held for other MACs are skipped. Addresses in quarantine are
+    // only handed out once nothing else is free, least recently released first. Otherwise the pool's
+    // strategy places the address, a hashed one hashing the MAC, which it then can't do without
+    pub fn allocate_free_address(pool_id: &String, mac: Option<&str>, sticky_idle: Option<Duration>, reuse: ReusePolicy, now: SystemTime, db: &mut dyn Store) -> IpamResult<IpInet> {
+        let mut selection = Scope::select(pool_id, db)?;
+        let (sticky, held, quarantined) = match selection.selected_description() {
+            Some(description) => {
+                let bindings = match sticky_idle {
+                    Some(idle) => description.live_mac_bindings(idle, now),
+                    None => Vec::new()
+                };
+                let allocated: HashSet<&String> = description.addresses.iter().map(|a| &a.address).collect();
+
+                let sticky = bindings
+                    .iter()
+                    .find(|b| Some(b.mac.as_str()) == mac && !allocated.contains(&b.address))
+                    .map(|b| b.address.clone());
+                let mut held: Vec<&MacBinding> = bindings
+                    .iter()
+                    .filter(|b| Some(b.mac.as_str()) != mac && !allocated.contains(&b.address))
+                    .collect();
+                held.sort_by_key(|b| b.last_used);
+                let held: Vec<String> = held.into_iter().map(|b| b.address.clone()).collect();
+
+                (sticky, held, description.quarantined(reuse, now))
+            }
+            None => {
+                return Err(IpamError::NoSuchPool(pool_id.clone()))
+            }
+        };
+
+        let address = match sticky {
+            Some(address) => address,
+            None => selection.next_free_address_avoiding(&held, &quarantined, mac)?.to_string()
+        };
+        let allocated = Scope::reserve_in(&mut selection, Some(address), AddressKind::Endpoint, db)?;
+
+        if let (Some(mac), Some(idle)) = (mac, sticky_idle) {
+            match selection.selected_description() {
+                Some(description) => description.bind_mac(mac, &allocated.address(), idle, now),
+                None => return Err(IpamError::NoSuchPool(pool_id.clone()))
+            }
+        }
+
+        selection.actual.modified = now;
+        Scope::compare_and_save(&mut selection, db)?;
+        Ok(allocated)
+    }
+
+    // an address with a lease is reclaimed by expire_leases unless it is renewed before it runs out
+    pub fn renew_lease(pool_id: &String, address: &IpAddr, expires: SystemTime, now: SystemTime, db: &mut dyn Store) -> IpamResult<()> {
+        let mut selection = Scope::select(pool_id, db)?;
+
+        match selection.selected_description() {
+            Some(description) => {
+                match description.addresses.iter_mut().find(|a| a.address == address.to_string()) {
+                    Some(reserved) if reserved.kind == AddressKind::Endpoint => {
+                        reserved.expires = Some(expires);
+                    }
+                    Some(_) => {
+                        return Err(IpamError::InvalidRequest(format!("{} is not an endpoint address", address)))
+                    }
+                    None => {
+                        return Err(IpamError::InvalidRequest(format!("{} is not allocated in {}", address, pool_id)))
+                    }
+                }
+            }
+            None => {
+                return Err(IpamError::NoSuchPool(pool_id.clone()))
+            }
+        }
+
+        selection.actual.modified = now;
+        Scope::compare_and_save(&mut selection, db)
+    }
+
+    pub fn is_leased(pool_id: &String, address: &IpAddr, db: &mut dyn Store) -> IpamResult<bool> {
+        match Scope::select(pool_id, db)?.selected_description() {
+            Some(description) => Ok(description.addresses.iter().any(|a| a.address == address.to_string() && a.expires.is_some())),
+            None => Err(IpamError::NoSuchPool(pool_id.clone()))
+        }
+    }
+
+    // releases every address whose lease ran out by now, returns the pool and address of each
+    pub fn expire_leases(now: SystemTime, db: &mut dyn Store) -> IpamResult<Vec<(String, String)>> {
+        let mut expired: Vec<(String, String)> = Vec::new();
+
+        for mut selection in Scope::records(db)? {
+            let (family, id) = (selection.actual.family, selection.actual.id);
+            let mut changed = false;
+
+            for description in selection.actual.descriptions.iter_mut() {
+                let pool_id = format!("{:#}", util::id_to_ip_cidr(family, id, description.prefix_length)?);
+                let addresses: Vec<String> = description.addresses
+                    .iter()
+                    .filter(|a| a.expires.map_or(false, |e| e <= now))
+                    .map(|a| a.address.clone())
+                    .collect();
+
+                for address in addresses {
+                    description.free(&address, now);
+                    expired.push((pool_id.clone(), address));
+                    changed = true;
+                }
+            }
+
+            if changed {
+                selection.actual.modified = now;
+                Scope::compare_and_save(&mut selection, db)?;
+            }
+        }
+
+        Ok(expired)
+    }
+
+    fn is_host_address(pool: &IpCidr, address: &IpAddr) -> bool {
+        match address {
+            IpAddr::V4(_) if pool.network_length() < 31 => {
+                *address != pool.first_address() && *address != pool.last_address()
+            }
+            IpAddr::V6(_) if pool.network_length() < 127 => {
+                *address != pool.first_address()
+            }
+            _ => true
+        }
+    }
+
+    fn proto_scope_with_prefix_length(&self, prefix_length: u8) -> IpamResult<ProtoScope<IpCidr>> {
+        ProtoScope::new_type_backed_proto_scope(util::id_to_ip_cidr(self.family, self.id, prefix_length)?)
+    }
+}
+
+impl Selection<Scope> {
+    pub fn selected_description(&mut self) -> Option<&mut ScopeDescription> {
+        let prefix_length = self.selected_prefix_length?;
+
+        self.actual.descriptions
+            .iter_mut()
+            .find(|d| d.prefix_length == prefix_length)
+    }
+
+    pub fn pool(&self) -> IpamResult<IpCidr> {
+        match self.selected_prefix_length {
+            Some(prefix_length) => {
+                match self.actual.proto_scope_with_prefix_length(prefix_length)?.cidr {
+                    Some(cidr) => Ok(cidr),
+                    None => Err(IpamError::InvalidCidr("uninitalized protoscope has no pool".to_string()))
+                }
+            }
+            None => Err(IpamError::InvalidRequest("no prefix length selected".to_string()))
+        }
+    }
+
+    pub fn pool_id(&self) -> IpamResult<String> {
+        Ok(format!("{:#}", self.pool()?))
+    }
+
+    pub fn next_free_address(&mut self) -> IpamResult<IpAddr> {
+        self.next_free_address_excluding(&HashSet::new(), None)
+    }
+
+    // quarantined addresses are the last resort, oldest first, and after them the addresses still
+    // held for other MACs, least recently used first
+    fn next_free_address_avoiding(&mut self, held: &Vec<String>, quarantined: &Vec<String>, key: Option<&str>) -> IpamResult<IpAddr> {
+        let mut excluded: HashSet<String> = held.iter().cloned().collect();
+        excluded.extend(quarantined.iter().cloned());
+
+        match self.next_free_address_excluding(&excluded, key) {
+            Err(IpamError::PoolExhausted(range)) => {
+                match quarantined.iter().find(|a| !held.contains(*a)).or(held.first()) {
+                    Some(address) => util::string_to_ip_addr(address.clone()),
+                    None => Err(IpamError::PoolExhausted(range))
+                }
+            }
+            other => other
+        }
+    }
+
+    // the pool's strategy picks where in the range to start, and the scan wraps around from there
+    fn next_free_address_excluding(&mut self, excluded: &HashSet<String>, key: Option<&str>) -> IpamResult<IpAddr> {
+        let pool = self.pool()?;
+        let pool_id = self.pool_id()?;
+        let description = match self.selected_description() {
+            Some(description) => description,
+            None => return Err(IpamError::NoSuchPool(pool_id))
+        };
+
+        let range = match &description.sub_pool {
+            Some(sub_pool) => IpCidr::from_str(sub_pool)?,
+            None => pool
+        };
+        let size = match range.is_ipv6() {
+            true => 1u128.checked_shl(128 - u32::from(range.network_length())).unwrap_or(u128::MAX),
+            false => 1u128 << (32 - u32::from(range.network_length()))
+        };
+        let used: HashSet<&String> = description.addresses.iter().map(|a| &a.address).collect();
+        let start = match description.strategy()?.start(size, key) {
+            Some(start) => start,
+            None => return Err(IpamError::InvalidRequest(format!("hashed placement in {} needs the endpoint's MAC address", pool_id)))
+        };
+        let mut candidate = util::offset_address(range.first_address(), start)?;
+        let mut scanned: u128 = 0;
+
+        loop {
+            if Scope::is_host_address(&pool, &candidate)
+                && !used.contains(&candidate.to_string())
+                && !excluded.contains(&candidate.to_string()) {
+                return Ok(candidate);
+            }
+
+            scanned += 1;
+
+            if scanned == size {
+                return Err(IpamError::PoolExhausted(format!("{:#}", range)));
+            }
+
+            candidate = match candidate == range.last_address() {
+                true => range.first_address(),
+                false => util::increment_address(candidate)?
+            };
+        }
+    }
+}
+
+impl locking_operations for Selection<Scope> {
+    fn lock(&mut self, lock: Lock) -> IpamResult<bool> {
+        let pool_id = self.pool_id()?;
+
+        match self.selected_description() {
+            Some(description) => description.lock(lock),
+            None => Err(IpamError::NoSuchPool(pool_id))
+        }
+    }
+
+    fn unlock(&mut self) -> IpamResult<bool> {
+        let pool_id = self.pool_id()?;
+
+        match self.selected_description() {
+            Some(description) => description.unlock(),
+            None => Err(IpamError::NoSuchPool(pool_id))
+        }
+    }
+
+    fn is_locked(&self) -> IpamResult<bool> {
+        match self.selected_prefix_length {
+            Some(prefix_length) => {
+                match self.actual.descriptions.iter().find(|d| d.prefix_length == prefix_length) {
+                    Some(description) => description.is_locked(),
+                    None => Err(IpamError::NoSuchPool(self.pool_id()?))
+                }
+            }
+            None => Err(IpamError::InvalidRequest("no prefix length selected".to_string()))
+        }
+    }
+}
+
+impl ScopeDescription {
+    // a free, unlocked and untagged pool of the given size
+    pub fn new(prefix_length: u8) -> ScopeDescription {
+        ScopeDescription {
+            prefix_length,
+            locked: false,
+            allocated: false,
+            tags: Vec::new(),
+            addresses: Vec::new(),
+            sub_pool: None,
+            lock: None,
+            request: None,
+            mac_bindings: Vec::new(),
+            released: Vec::new(),
+            strategy: None,
+            claimed: false
+        }
+    }
+
+    fn bind_mac(&mut self, mac: &str, address: &IpAddr, idle: Duration, now: SystemTime) {
+        let mut bindings = self.live_mac_bindings(idle, now);
+        bindings.retain(|b| b.mac != mac && b.address != address.to_string());
+        bindings.push(MacBinding {
+            mac: mac.to_string(),
+            address: address.to_string(),
+            last_used: now
+        });
+        self.mac_bindings = bindings;
+    }
+
+    // a strategy the network was requested with wins over the one its schema was configured with
+    pub fn strategy(&self) -> IpamResult<Strategy> {
+        match self.request.as_ref().and_then(|r| r.options.get(STRATEGY_OPTION)) {
+            Some(strategy) => Strategy::parse(strategy),
+            None => Ok(self.strategy.unwrap_or_default())
+        }
+    }
+
+    // the MAC binding of a freed address starts idling, and the address goes into quarantine
+    fn free(&mut self, address: &String, at: SystemTime) {
+        self.addresses.retain(|a| a.address != *address);
+
+        for binding in self.mac_bindings.iter_mut().filter(|b| b.address == *address) {
+            binding.last_used = at;
+        }
+
+        self.released.retain(|r| r.address != *address);
+        self.released.push(ReleasedAddress {
+            address: address.clone(),
+            at
+        });
+    }
+
+    // free addresses that should not be handed out yet, least recently released first
+    fn quarantined(&self, reuse: ReusePolicy, now: SystemTime) -> Vec<String> {
+        let mut released: Vec<&ReleasedAddress> = self.released
+            .iter()
+            .filter(|r| match reuse {
+                ReusePolicy::Immediate => false,
+                ReusePolicy::Cooldown(cooldown) => r.at + cooldown > now,
+                ReusePolicy::LeastRecentlyUsed => true
+            })
+            .filter(|r| !self.addresses.iter().any(|a| a.address == r.address))
+            .collect();
+
+        released.sort_by_key(|r| r.at);
+        released.iter().map(|r| r.address.clone()).collect()
+    }
+
+    // a binding lasts while its address is allocated and for idle after it was released
+    fn live_mac_bindings(&self, idle: Duration, now: SystemTime) -> Vec<MacBinding> {
+        self.mac_bindings
+            .iter()
+            .filter(|b| self.addresses.iter().any(|a| a.address == b.address) || b.last_used + idle > now)
+            .cloned()
+            .collect()
+    }
+}
+
+impl locking_operations for ScopeDescription {
+    fn lock(&mut self, lock: Lock) -> IpamResult<bool> {
+        let changed = !self.locked;
+        self.locked = true;
+        self.lock = Some(lock);
+        Ok(changed)
+    }
+
+    fn unlock(&mut self) -> IpamResult<bool> {
+        let changed = self.locked;
+        self.locked = false;
+        self.lock = None;
+        Ok(changed)
+    }
+
+    fn is_locked(&self) -> IpamResult<bool> {
+        Ok(self.locked)
+    }
+}
+
 #[cfg(test)]
 mod data_store_tests {
-    use log::warn;
     use crate::scope::*;
+    use crate::storage::{MemoryStore, Transaction};
     
     #[test]
     fn db_is_not_initialized() {
-        std::env::set_var("SCOPE_DB_FILE", "");     
-        let mut dao = Schema::dao().unwrap();
+        let mut dao = MemoryStore::new();
         assert_eq!(Scope::is_db_initialized(&mut dao).unwrap(), false);        
     }
 
     #[test]
     fn db_is_initialized() {
-        std::env::set_var("SCOPE_DB_FILE", "");     
-        let mut dao = Schema::dao().unwrap();
-        todo!()
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
+        util::create_initial_scopes(&mut dao, &mut schema_dao).unwrap();
+        assert_eq!(Scope::is_db_initialized(&mut dao).unwrap(), true);
     }
+    #[test]
+    fn select_by_pool_id() {
+        let mut dao = MemoryStore::new();
+        let mut selection = Scope::new_from_proto_scope(
+            ProtoScope::new_type_backed_proto_scope(IpCidr::from_str("100.64.16.0/20").unwrap()).unwrap(),
+            None).unwrap();
+
+        selection.actual.descriptions.push(ScopeDescription {
+            allocated: true,
+            ..ScopeDescription::new(20)
+        });
+        Scope::save(&mut selection, &mut dao).unwrap();
+
+        let mut selected = Scope::select(&"100.64.16.0/20".to_string(), &mut dao).unwrap();
+        assert_eq!(selected.pool_id().unwrap(), "100.64.16.0/20");
+        assert_eq!(selected.selected_description().unwrap().allocated, true);
+        assert_eq!(Scope::select(&"100.64.16.0/24".to_string(), &mut dao).is_err(), true);
+        assert_eq!(Scope::select(&"100.64.32.0/20".to_string(), &mut dao).is_err(), true);
+    }
+
+    #[test]
+    fn reserve_gateway_and_aux_addresses() {
+        let mut dao = MemoryStore::new();
+        let mut selection = Scope::new_from_proto_scope(
+            ProtoScope::new_type_backed_proto_scope(IpCidr::from_str("100.64.16.0/20").unwrap()).unwrap(),
+            None).unwrap();
+
+        selection.actual.descriptions.push(ScopeDescription {
+            allocated: true,
+            ..ScopeDescription::new(20)
+        });
+        Scope::save(&mut selection, &mut dao).unwrap();
+
+        let pool_id = "100.64.16.0/20".to_string();
+        let gateway = Scope::reserve_address(&pool_id, None, AddressKind::Gateway, SystemTime::now(), &mut dao).unwrap();
+        assert_eq!(format!("{:#}", gateway), "100.64.16.1/20");
+        assert_eq!(Scope::reserve_address(&pool_id, Some("100.64.16.1".to_string()), AddressKind::Gateway, SystemTime::now(), &mut dao).unwrap(), gateway);
+
+        Scope::reserve_address(&pool_id, Some("100.64.16.5".to_string()), AddressKind::Endpoint, SystemTime::now(), &mut dao).unwrap();
+        assert_eq!(
+            Scope::reserve_address(&pool_id, Some("100.64.16.5".to_string()), AddressKind::Endpoint, SystemTime::now(), &mut dao).unwrap_err(),
+            IpamError::AddressInUse("100.64.16.5".to_string()));
+        assert_eq!(
+            Scope::reserve_address(&pool_id, Some("100.64.16.1".to_string()), AddressKind::Endpoint, SystemTime::now(), &mut dao).unwrap_err(),
+            IpamError::AddressInUse("100.64.16.1".to_string()));
+        assert_eq!(Scope::reserve_address(&pool_id, Some("100.64.32.1".to_string()), AddressKind::Endpoint, SystemTime::now(), &mut dao).is_err(), true);
+
+        let mut selected = Scope::select(&pool_id, &mut dao).unwrap();
+        assert_eq!(selected.selected_description().unwrap().addresses.len(), 2);
+    }
+
+    #[test]
+    fn claim_preferred_pool() {
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
+
+        let pool = IpCidr::from_str("100.64.16.0/20").unwrap();
+        let sub_pool = IpCidr::from_str("100.64.17.0/24").unwrap();
+        let mut claimed = Scope::claim_pool(pool, Some(sub_pool), &mut dao, &schema_dao).unwrap();
+        assert_eq!(claimed.pool_id().unwrap(), "100.64.16.0/20");
+        assert_eq!(claimed.selected_description().unwrap().sub_pool, Some("100.64.17.0/24".to_string()));
+
+        assert_eq!(
+            Scope::claim_pool(IpCidr::from_str("100.64.18.0/24").unwrap(), None, &mut dao, &schema_dao).err(),
+            Some(IpamError::PoolOverlap("100.64.18.0/24".to_string())));
+        assert_eq!(
+            Scope::claim_pool(IpCidr::from_str("100.64.0.0/17").unwrap(), None, &mut dao, &schema_dao).err(),
+            Some(IpamError::PoolOverlap("100.64.0.0/17".to_string())));
+        assert_eq!(Scope::claim_pool(IpCidr::from_str("10.0.0.0/24").unwrap(), None, &mut dao, &schema_dao).is_err(), true);
+        assert_eq!(Scope::claim_pool(
+            IpCidr::from_str("100.64.32.0/20").unwrap(),
+            Some(IpCidr::from_str("100.64.48.0/24").unwrap()),
+            &mut dao,
+            &schema_dao).is_err(), true);
+        assert_eq!(Scope::claim_pool(IpCidr::from_str("100.64.32.0/24").unwrap(), None, &mut dao, &schema_dao).is_ok(), true);
+    }
+
+    #[test]
+    fn released_claims_do_not_become_dynamic_pools() {
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
+        util::create_initial_scopes(&mut dao, &mut schema_dao).unwrap();
+
+        let claimed = Scope::claim_pool(IpCidr::from_str("100.64.0.0/24").unwrap(), None, &mut dao, &schema_dao).unwrap();
+        Scope::release_pool(claimed.pool_id().unwrap(), &mut dao).unwrap();
+        assert_eq!(Scope::allocate_tagged_pool(&Vec::new(), false, &mut dao).unwrap().pool_id().unwrap(), "100.64.0.0/20");
+
+        let claimed = Scope::claim_pool(IpCidr::from_str("100.64.16.128/25").unwrap(), None, &mut dao, &schema_dao).unwrap();
+        Scope::release_pool(claimed.pool_id().unwrap(), &mut dao).unwrap();
+        assert_eq!(Scope::exists_in_database(Family::V4, claimed.actual.id, &dao).unwrap(), false);
+        assert_eq!(Scope::allocate_tagged_pool(&Vec::new(), false, &mut dao).unwrap().pool_id().unwrap(), "100.64.16.0/20");
+    }
+
+    #[test]
+    fn allocate_host_addresses() {
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
+        Scope::claim_pool(IpCidr::from_str("100.64.16.0/29").unwrap(), None, &mut dao, &schema_dao).unwrap();
+
+        let pool_id = "100.64.16.0/29".to_string();
+        Scope::reserve_address(&pool_id, None, AddressKind::Gateway, SystemTime::now(), &mut dao).unwrap();
+        Scope::reserve_address(&pool_id, Some("100.64.16.3".to_string()), AddressKind::Endpoint, SystemTime::now(), &mut dao).unwrap();
+
+        let allocated: Vec<String> = (0..4)
+            .map(|_| format!("{:#}", Scope::reserve_address(&pool_id, None, AddressKind::Endpoint, SystemTime::now(), &mut dao).unwrap()))
+            .collect();
+        assert_eq!(allocated, vec!["100.64.16.2/29", "100.64.16.4/29", "100.64.16.5/29", "100.64.16.6/29"]);
+
+        assert_eq!(
+            Scope::reserve_address(&pool_id, None, AddressKind::Endpoint, SystemTime::now(), &mut dao).unwrap_err(),
+            IpamError::PoolExhausted("100.64.16.0/29".to_string()));
+        assert_eq!(Scope::reserve_address(&pool_id, Some("100.64.16.7".to_string()), AddressKind::Endpoint, SystemTime::now(), &mut dao).is_err(), true);
+        assert_eq!(Scope::reserve_address(&pool_id, Some("100.64.16.0".to_string()), AddressKind::Endpoint, SystemTime::now(), &mut dao).is_err(), true);
+
+        let mut selected = Scope::select(&pool_id, &mut dao).unwrap();
+        assert_eq!(selected.selected_description().unwrap().addresses.len(), 6);
+    }
+
+    #[test]
+    fn allocate_from_sub_pool() {
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
+        Scope::claim_pool(
+            IpCidr::from_str("100.64.16.0/20").unwrap(),
+            Some(IpCidr::from_str("100.64.17.0/24").unwrap()),
+            &mut dao,
+            &schema_dao).unwrap();
+
+        let allocated = Scope::reserve_address(&"100.64.16.0/20".to_string(), None, AddressKind::Endpoint, SystemTime::now(), &mut dao).unwrap();
+        assert_eq!(format!("{:#}", allocated), "100.64.17.0/20");
+
+        // the next network handed the same pool dynamically gets all of it
+        let mut dao = MemoryStore::new();
+        util::create_initial_scopes(&mut dao, &mut schema_dao).unwrap();
+        let pool_id = "100.64.0.0/20".to_string();
+        Scope::claim_pool(IpCidr::from_str(&pool_id).unwrap(), Some(IpCidr::from_str("100.64.1.0/24").unwrap()), &mut dao, &schema_dao).unwrap();
+        Scope::release_pool(pool_id.clone(), &mut dao).unwrap();
+
+        assert_eq!(Scope::allocate_placed_pool(&Vec::new(), false, None, None, &mut dao).unwrap().pool_id().unwrap(), pool_id);
+        let allocated = Scope::reserve_address(&pool_id, None, AddressKind::Endpoint, SystemTime::now(), &mut dao).unwrap();
+        assert_eq!(format!("{:#}", allocated), "100.64.0.1/20");
+    }
+
+    #[test]
+    fn allocate_pools_by_tag() {
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        let mut root = Schema::new_from_string("100.64.0.0".to_string(), 17, None).unwrap();
+        let mut tagged = Schema::new_from_string("100.64.128.0".to_string(), 19, None).unwrap();
+        tagged.actual.descriptions[0].tags = vec!["team-a".to_string(), "edge".to_string()];
+        Schema::save(&mut root, &mut schema_dao).unwrap();
+        Schema::save(&mut tagged, &mut schema_dao).unwrap();
+        util::create_initial_scopes(&mut dao, &mut schema_dao).unwrap();
+
+        let mut untagged = Scope::allocate_tagged_pool(&Vec::new(), false, &mut dao).unwrap();
+        assert_eq!(untagged.pool_id().unwrap(), "100.64.0.0/17");
+        assert_eq!(untagged.selected_description().unwrap().allocated, true);
+
+        let mut team = Scope::allocate_tagged_pool(&vec!["team-a".to_string()], false, &mut dao).unwrap();
+        assert_eq!(team.pool_id().unwrap(), "100.64.128.0/19");
+        assert_eq!(team.selected_description().unwrap().tags, vec!["team-a", "edge"]);
+
+        assert_eq!(
+            Scope::allocate_tagged_pool(&vec!["team-a".to_string()], false, &mut dao).err(),
+            Some(IpamError::PoolExhausted("IPv4 pools tagged team-a".to_string())));
+        assert_eq!(Scope::allocate_tagged_pool(&vec!["team-b".to_string()], false, &mut dao).is_err(), true);
+        assert_eq!(Scope::select(&"100.64.128.0/19".to_string(), &mut dao).unwrap().selected_description().unwrap().allocated, true);
+    }
+
+    #[test]
+    fn allocate_leaf_pools_first() {
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
+        util::create_initial_scopes(&mut dao, &mut schema_dao).unwrap();
+
+        let pools: Vec<String> = (0..8)
+            .map(|_| Scope::allocate_tagged_pool(&Vec::new(), false, &mut dao).unwrap().pool_id().unwrap())
+            .collect();
+        assert_eq!(pools.first().unwrap(), "100.64.0.0/20");
+        assert_eq!(pools.last().unwrap(), "100.64.112.0/20");
+        assert_eq!(Scope::allocate_tagged_pool(&Vec::new(), false, &mut dao).is_err(), true);
+    }
+
+    #[test]
+    fn allocate_ipv6_pools() {
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        let config = crate::config::SchemaConfig::from_toml(r#"
+            [[root]]
+            network = "100.64.0.0/23"
+            prefix_length = 24
+
+            [[root]]
+            network = "fd00:1::/56"
+            prefix_length = 64
+        "#).unwrap();
+        Schema::seed(&mut schema_dao, &config).unwrap();
+        util::create_initial_scopes(&mut dao, &mut schema_dao).unwrap();
+
+        let v6 = Scope::allocate_tagged_pool(&Vec::new(), true, &mut dao).unwrap();
+        let v4 = Scope::allocate_tagged_pool(&Vec::new(), false, &mut dao).unwrap();
+        assert_eq!(v6.pool_id().unwrap(), "fd00:1::/64");
+        assert_eq!(v4.pool_id().unwrap(), "100.64.0.0/24");
+
+        let pool_id = v6.pool_id().unwrap();
+        let gateway = Scope::reserve_address(&pool_id, None, AddressKind::Gateway, SystemTime::now(), &mut dao).unwrap();
+        let endpoint = Scope::reserve_address(&pool_id, None, AddressKind::Endpoint, SystemTime::now(), &mut dao).unwrap();
+        assert_eq!(format!("{:#}", gateway), "fd00:1::1/64");
+        assert_eq!(format!("{:#}", endpoint), "fd00:1::2/64");
+    }
+
+    #[test]
+    fn locked_pools_refuse_new_allocations() {
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
+        util::create_initial_scopes(&mut dao, &mut schema_dao).unwrap();
+
+        let pool_id = Scope::allocate_tagged_pool(&Vec::new(), false, &mut dao).unwrap().pool_id().unwrap();
+        let address = Scope::reserve_address(&pool_id, None, AddressKind::Endpoint, SystemTime::now(), &mut dao).unwrap();
+        let nested = Scope::claim_pool(IpCidr::from_str("100.64.16.0/24").unwrap(), None, &mut dao, &schema_dao).unwrap().pool_id().unwrap();
+        let lock = Lock {
+            by: "alice".to_string(),
+            reason: "renumbering".to_string(),
+            since: SystemTime::now()
+        };
+
+        let root = IpCidr::from_str("100.64.0.0/17").unwrap();
+        assert_eq!(util::set_lock(&root, Some(lock.clone()), &mut dao, &mut schema_dao).unwrap(), true);
+        assert_eq!(Schema::node(&root, &schema_dao).unwrap().unwrap().is_locked().unwrap(), true);
+        assert_eq!(
+            Scope::reserve_address(&pool_id, None, AddressKind::Endpoint, SystemTime::now(), &mut dao).err(),
+            Some(IpamError::SchemaLocked("100.64.0.0/17 (locked by alice: renumbering)".to_string())));
+        assert_eq!(
+            Scope::reserve_address(&nested, None, AddressKind::Endpoint, SystemTime::now(), &mut dao).err(),
+            Some(IpamError::SchemaLocked("100.64.0.0/17 (locked by alice: renumbering)".to_string())));
+        assert_eq!(Scope::allocate_tagged_pool(&Vec::new(), false, &mut dao).is_err(), true);
+        assert_eq!(
+            Scope::claim_pool(IpCidr::from_str("100.64.32.0/24").unwrap(), None, &mut dao, &schema_dao).err(),
+            Some(IpamError::SchemaLocked("100.64.0.0/17 (locked by alice: renumbering)".to_string())));
+
+        assert_eq!(Scope::select(&pool_id, &mut dao).unwrap().selected_description().unwrap().addresses[0].address, address.address().to_string());
+
+        let locks = util::locks(&dao, &schema_dao).unwrap();
+        assert_eq!(locks, vec![(root, Some(lock))]);
+
+        assert_eq!(util::set_lock(&root, None, &mut dao, &mut schema_dao).unwrap(), true);
+        let leaf = IpCidr::from_str("100.64.16.0/20").unwrap();
+        util::set_lock(&leaf, Some(Lock {
+            by: "bob".to_string(),
+            reason: "reserved".to_string(),
+            since: SystemTime::now()
+        }), &mut dao, &mut schema_dao).unwrap();
+        assert_eq!(Scope::reserve_address(&pool_id, None, AddressKind::Endpoint, SystemTime::now(), &mut dao).is_ok(), true);
+        assert_eq!(Scope::allocate_tagged_pool(&Vec::new(), false, &mut dao).unwrap().pool_id().unwrap(), "100.64.32.0/20");
+        assert_eq!(util::set_lock(&IpCidr::from_str("10.0.0.0/8").unwrap(), None, &mut dao, &mut schema_dao).err(),
+            Some(IpamError::NoSuchPool("10.0.0.0/8".to_string())));
+    }
+
+    #[test]
+    fn stale_selections_are_not_saved() {
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
+        Scope::claim_pool(IpCidr::from_str("100.64.16.0/20").unwrap(), None, &mut dao, &schema_dao).unwrap();
+
+        let pool_id = "100.64.16.0/20".to_string();
+        let mut first = Scope::select(&pool_id, &mut dao).unwrap();
+        let mut second = Scope::select(&pool_id, &mut dao).unwrap();
+
+        Scope::compare_and_save(&mut first, &mut dao).unwrap();
+        assert_eq!(
+            Scope::compare_and_save(&mut second, &mut dao).err(),
+            Some(IpamError::StaleRecord(pool_id.clone())));
+        assert_eq!(Scope::select(&pool_id, &mut dao).unwrap().actual.revision, 2);
+    }
+
+    #[test]
+    fn sticky_addresses_follow_their_mac() {
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
+        Scope::claim_pool(IpCidr::from_str("100.64.16.0/29").unwrap(), None, &mut dao, &schema_dao).unwrap();
+
+        let pool_id = "100.64.16.0/29".to_string();
+        let idle = Duration::from_secs(60);
+        let now = SystemTime::now();
+        let first = Scope::allocate_free_address(&pool_id, Some("02:42:ac:11:00:02"), Some(idle), ReusePolicy::Immediate, now, &mut dao).unwrap();
+        assert_eq!(format!("{:#}", first), "100.64.16.1/29");
+        Scope::release_address(pool_id.clone(), "100.64.16.1".to_string(), now, &mut dao).unwrap();
+
+        let other = Scope::allocate_free_address(&pool_id, None, Some(idle), ReusePolicy::Immediate, now, &mut dao).unwrap();
+        assert_eq!(format!("{:#}", other), "100.64.16.2/29");
+        assert_eq!(Scope::allocate_free_address(&pool_id, Some("02:42:ac:11:00:02"), Some(idle), ReusePolicy::Immediate, now, &mut dao).unwrap(), first);
+        Scope::release_address(pool_id.clone(), "100.64.16.1".to_string(), now, &mut dao).unwrap();
+
+        let later = now + Duration::from_secs(120);
+        assert_eq!(Scope::allocate_free_address(&pool_id, Some("02:42:ac:11:00:03"), Some(idle), ReusePolicy::Immediate, later, &mut dao).unwrap(), first);
+
+        let bindings = Scope::select(&pool_id, &mut dao).unwrap().selected_description().unwrap().mac_bindings.clone();
+        assert_eq!(bindings.len(), 1);
+        assert_eq!(bindings[0].mac, "02:42:ac:11:00:03");
+
+        // once nothing else is free, the address bound longest ago goes first
+        let pool_id = "100.64.16.8/30".to_string();
+        Scope::claim_pool(IpCidr::from_str(&pool_id).unwrap(), None, &mut dao, &schema_dao).unwrap();
+        for (mac, released) in vec![("02:42:ac:11:00:04", now + Duration::from_secs(10)), ("02:42:ac:11:00:05", now)] {
+            let address = Scope::allocate_free_address(&pool_id, Some(mac), Some(idle), ReusePolicy::Immediate, now, &mut dao).unwrap();
+            Scope::release_address(pool_id.clone(), address.address().to_string(), released, &mut dao).unwrap();
+        }
+
+        let later = now + Duration::from_secs(20);
+        let taken: Vec<String> = vec!["02:42:ac:11:00:06", "02:42:ac:11:00:07"]
+            .into_iter()
+            .map(|mac| Scope::allocate_free_address(&pool_id, Some(mac), Some(idle), ReusePolicy::Immediate, later, &mut dao).unwrap().address().to_string())
+            .collect();
+        assert_eq!(taken, vec!["100.64.16.10", "100.64.16.9"]);
+        assert_eq!(Scope::allocate_free_address(&pool_id, None, Some(idle), ReusePolicy::Immediate, later, &mut dao).is_err(), true);
+    }
+
+    #[test]
+    fn released_addresses_are_quarantined() {
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
+        Scope::claim_pool(IpCidr::from_str("100.64.16.0/29").unwrap(), None, &mut dao, &schema_dao).unwrap();
+        Scope::claim_pool(IpCidr::from_str("100.64.16.8/29").unwrap(), None, &mut dao, &schema_dao).unwrap();
+
+        let pool_id = "100.64.16.0/29".to_string();
+        let now = SystemTime::now();
+        let allocate = |reuse: ReusePolicy, now: SystemTime, dao: &mut MemoryStore| {
+            format!("{:#}", Scope::allocate_free_address(&pool_id, None, None, reuse, now, dao).unwrap().address())
+        };
+
+        assert_eq!(allocate(ReusePolicy::LeastRecentlyUsed, now, &mut dao), "100.64.16.1");
+        assert_eq!(allocate(ReusePolicy::LeastRecentlyUsed, now, &mut dao), "100.64.16.2");
+        Scope::release_address(pool_id.clone(), "100.64.16.2".to_string(), now, &mut dao).unwrap();
+        Scope::release_address(pool_id.clone(), "100.64.16.1".to_string(), now, &mut dao).unwrap();
+
+        let fresh: Vec<String> = (0..4).map(|_| allocate(ReusePolicy::LeastRecentlyUsed, now, &mut dao)).collect();
+        assert_eq!(fresh, vec!["100.64.16.3", "100.64.16.4", "100.64.16.5", "100.64.16.6"]);
+        assert_eq!(allocate(ReusePolicy::LeastRecentlyUsed, now, &mut dao), "100.64.16.2");
+        assert_eq!(allocate(ReusePolicy::LeastRecentlyUsed, now, &mut dao), "100.64.16.1");
+
+        let pool_id = "100.64.16.8/29".to_string();
+        let cooldown = ReusePolicy::Cooldown(Duration::from_secs(60));
+        let first = Scope::allocate_free_address(&pool_id, None, None, cooldown, now, &mut dao).unwrap();
+        Scope::release_address(pool_id.clone(), first.address().to_string(), now, &mut dao).unwrap();
+
+        assert_eq!(format!("{:#}", Scope::allocate_free_address(&pool_id, None, None, cooldown, now, &mut dao).unwrap()), "100.64.16.10/29");
+        assert_eq!(Scope::allocate_free_address(&pool_id, None, None, cooldown, now + Duration::from_secs(120), &mut dao).unwrap(), first);
+        assert_eq!(Scope::select(&pool_id, &mut dao).unwrap().selected_description().unwrap().released.is_empty(), true);
+    }
+
+    #[test]
+    fn strategies_place_pools_and_addresses() {
+        let place = |strategy: Strategy, key: Option<&str>| {
+            let mut dao = MemoryStore::new();
+            let mut schema_dao = MemoryStore::new();
+            Schema::initialize_db(&mut schema_dao).unwrap();
+            util::create_initial_scopes(&mut dao, &mut schema_dao).unwrap();
+            Scope::allocate_placed_pool(&Vec::new(), false, Some(strategy), key, &mut dao).unwrap().pool_id().unwrap()
+        };
+
+        assert_eq!(place(Strategy::FirstFit, Some("net-a")), "100.64.0.0/20");
+        assert_eq!(place(Strategy::Hashed, Some("net-a")), "100.64.32.0/20");
+        assert_eq!(place(Strategy::Hashed, Some("net-b")), "100.64.112.0/20");
+        assert_eq!(place(Strategy::Hashed, None), "100.64.0.0/20");
+        assert_eq!(Strategy::Hashed.start(8, None), None);
+        assert_eq!(Strategy::parse("best-fit").is_err(), true);
+
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
+
+        let hashed = "100.64.16.0/29".to_string();
+        let random = "100.64.16.8/29".to_string();
+        for (pool_id, strategy) in vec![(&hashed, "hashed"), (&random, "random")] {
+            Scope::claim_pool(IpCidr::from_str(pool_id).unwrap(), None, &mut dao, &schema_dao).unwrap();
+            Scope::remember_request(pool_id, None, RequestIdentity {
+                options: vec![(STRATEGY_OPTION.to_string(), strategy.to_string())].into_iter().collect()
+            }, SystemTime::now(), &mut dao).unwrap();
+        }
+
+        let now = SystemTime::now();
+        let mac = Some("02:42:ac:11:00:03");
+        let first = Scope::allocate_free_address(&hashed, mac, None, ReusePolicy::Immediate, now, &mut dao).unwrap();
+        assert_eq!(first.address().to_string(), "100.64.16.6");
+        Scope::release_address(hashed.clone(), first.address().to_string(), now, &mut dao).unwrap();
+        assert_eq!(Scope::allocate_free_address(&hashed, mac, None, ReusePolicy::Immediate, now, &mut dao).unwrap(), first);
+        assert_eq!(Scope::allocate_free_address(&hashed, mac, None, ReusePolicy::Immediate, now, &mut dao).unwrap().address().to_string(), "100.64.16.1");
+        assert_eq!(
+            Scope::allocate_free_address(&hashed, None, None, ReusePolicy::Immediate, now, &mut dao).err(),
+            Some(IpamError::InvalidRequest("hashed placement in 100.64.16.0/29 needs the endpoint's MAC address".to_string())));
+
+        let placed: HashSet<String> = (0..6)
+            .map(|_| Scope::allocate_free_address(&random, None, None, ReusePolicy::Immediate, now, &mut dao).unwrap().address().to_string())
+            .collect();
+        assert_eq!(placed.len(), 6);
+        assert_eq!(placed.contains("100.64.16.8") || placed.contains("100.64.16.15"), false);
+        assert_eq!(Scope::allocate_free_address(&random, None, None, ReusePolicy::Immediate, now, &mut dao).is_err(), true);
+    }
+
+    #[test]
+    fn replayed_requests_match_what_was_remembered() {
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
+
+        let pool_id = "100.64.16.0/20".to_string();
+        let sub_pool = Some(IpCidr::from_str("100.64.17.0/24").unwrap());
+        let request = RequestIdentity {
+            options: vec![("tags".to_string(), "a".to_string())].into_iter().collect()
+        };
+        assert_eq!(Scope::replayed_pool(&pool_id, sub_pool, &request, &mut dao).unwrap(), false);
+
+        Scope::claim_pool(IpCidr::from_str(&pool_id).unwrap(), sub_pool, &mut dao, &schema_dao).unwrap();
+        Scope::remember_request(&pool_id, None, request.clone(), SystemTime::now(), &mut dao).unwrap();
+        assert_eq!(Scope::replayed_pool(&pool_id, sub_pool, &request, &mut dao).unwrap(), true);
+        assert_eq!(Scope::replayed_pool(&pool_id, None, &request, &mut dao).unwrap(), false);
+        assert_eq!(Scope::replayed_pool(&pool_id, sub_pool, &RequestIdentity::default(), &mut dao).unwrap(), false);
+
+        let mac = RequestIdentity {
+            options: vec![("com.docker.network.endpoint.macaddress".to_string(), "02:42:ac:11:00:02".to_string())].into_iter().collect()
+        };
+        let address = Scope::reserve_address(&pool_id, None, AddressKind::Endpoint, SystemTime::now(), &mut dao).unwrap();
+        Scope::remember_request(&pool_id, Some(address.address()), mac.clone(), SystemTime::now(), &mut dao).unwrap();
+        assert_eq!(
+            Scope::replayed_address(&pool_id, &address.address().to_string(), AddressKind::Endpoint, &mac, &mut dao).unwrap(),
+            Some(address));
+        assert_eq!(
+            Scope::replayed_address(&pool_id, &address.address().to_string(), AddressKind::Endpoint, &RequestIdentity::default(), &mut dao).unwrap(),
+            None);
+
+        Scope::release_pool(pool_id.clone(), &mut dao).unwrap();
+        assert_eq!(Scope::replayed_pool(&pool_id, sub_pool, &request, &mut dao).unwrap(), false);
+    }
+
+    #[test]
+    fn families_do_not_collide() {
+        let mut dao = MemoryStore::new();
+
+        for pool in ["0.0.0.4/30", "::4/126"] {
+            let mut selection = Scope::new_from_proto_scope(
+                ProtoScope::new_type_backed_proto_scope(IpCidr::from_str(pool).unwrap()).unwrap(),
+                None).unwrap();
+            Scope::save(&mut selection, &mut dao).unwrap();
+        }
+
+        let records = Scope::records(&dao).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records.iter().any(|r| r.actual.family == Family::V4), true);
+        assert_eq!(records.iter().any(|r| r.actual.family == Family::V6), true);
+    }
+
+    #[test]
+    fn migrate_legacy_keys() {
+        let mut dao = MemoryStore::new();
+        let v4 = util::string_to_u128_id("100.64.16.0".to_string()).unwrap();
+        let v6 = util::string_to_u128_id("fd00::".to_string()).unwrap();
+
+        for id in [v4, v6] {
+            let record = serde_json::to_string(&Scope {
+                id: id,
+                family: Family::V4,
+                parent: None,
+                modified: SystemTime::now(),
+                created: SystemTime::now(),
+                descriptions: vec![ScopeDescription {
+                    allocated: true,
+                    ..ScopeDescription::new(24)
+                }],
+                revision: 0
+            }).unwrap().replace(r#""family":"V4","#, "");
+            dao.store(&id.to_be_bytes(), record.as_bytes()).unwrap();
+        }
+
+        assert_eq!(util::migrate_record_keys(&mut dao).unwrap(), 2);
+        assert_eq!(util::migrate_record_keys(&mut dao).unwrap(), 0);
+        assert_eq!(dao.contains(&v4.to_be_bytes()).unwrap(), false);
+        assert_eq!(Scope::select(&"100.64.16.0/24".to_string(), &mut dao).unwrap().actual.family, Family::V4);
+        assert_eq!(Scope::select(&"fd00::/24".to_string(), &mut dao).unwrap().actual.family, Family::V6);
+    }
+
     #[test]
     fn test_roll_back_tx() {
-        std::env::set_var("SCOPE_DB_FILE", "");
-        let mut dao = Scope::dao().unwrap();
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        Schema::initialize_db(&mut schema_dao).unwrap();
 
-        match Scope::begin_tx(&mut dao) {
-            Ok(_) => {
-                match Scope::initialize_db(&mut dao) {
-                    Ok(_) => {
-                        match Scope::roll_back_tx(&mut dao) {
-                            Ok(_) => (),
-                            Err(_) => panic!("rollback of init failed"),
-                        }
-                    }
-                    Err(_) => panic!("failed to initialize db"),
-                }
-            }
-            Err(_) => panic!("failed to begin tx"),
+        {
+            let mut tx = Transaction::begin(&mut dao, &mut schema_dao).unwrap();
+            let (scope, schema) = tx.stores();
+            util::create_initial_scopes(scope, schema).unwrap();
         }
+
+        assert_eq!(Scope::is_db_initialized(&mut dao).unwrap(), false);
+        assert_eq!(Schema::is_db_initialized(&mut schema_dao).unwrap(), true);
     }
 
     #[test]
     fn test_begin_tx_within_tx() {
-        std::env::set_var("SCOPE_DB_FILE", "");
-        let mut dao = Scope::dao().unwrap();
+        let mut dao = MemoryStore::new();
+        let mut schema_dao = MemoryStore::new();
+        let mut tx = Transaction::begin(&mut dao, &mut schema_dao).unwrap();
 
-        match Scope::begin_tx(&mut dao) {
-            Ok(_) => {
-                match Scope::begin_tx(&mut dao) {
-                    Ok(_) => warn!("is this bad or good"),
-                    Err(_) => panic!("unknown behavior")
-                }
-            }
-            Err(e) => panic!("failed to begin transaction: {} ", e)
-        }
+        assert_eq!(tx.stores().0.begin().is_err(), true);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,343 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use log::warn;
+use rocket::http::{Header, Method};
+use rocket::local::Client;
+use rocket::Rocket;
+
+#[derive(Clone)]
+pub(crate) struct SocketConfig {
+    pub path: PathBuf,
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+    pub mode: Option<u32>,
+    pub spec_file: Option<PathBuf>
+}
+
+pub(crate) fn bind(config: &SocketConfig) -> Result<UnixListener, Box<dyn Error>> {
+    remove_stale_socket(&config.path)?;
+
+    if let Some(parent) = config.path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let listener = UnixListener::bind(&config.path)?;
+
+    if config.owner.is_some() || config.group.is_some() {
+        std::os::unix::fs::chown(&config.path, config.owner, config.group)?;
+    }
+
+    if let Some(mode) = config.mode {
+        fs::set_permissions(&config.path, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(listener)
+}
+
+fn remove_stale_socket(path: &Path) -> Result<(), Box<dyn Error>> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => {
+            if !metadata.file_type().is_socket() {
+                return Err(format!("{} exists and is not a socket", path.display()).into());
+            }
+
+            match UnixStream::connect(path) {
+                Ok(_) => Err(format!("{} is in use by another process", path.display()).into()),
+                Err(_) => {
+                    fs::remove_file(path)?;
+                    Ok(())
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Box::new(e))
+    }
+}
+
+pub(crate) fn write_spec_file(spec_file: &Path, address: String) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = spec_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let contents = match spec_file.extension().and_then(|e| e.to_str()) {
+        Some("json") => {
+            let name = spec_file.file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default()
+                .to_string();
+
+            serde_json::to_string(&serde_json::json!({
+                "Name": name,
+                "Addr": address
+            }))?
+        }
+        Some("spec") => address,
+        _ => return Err(format!("{} must end in .spec or .json", spec_file.display()).into())
+    };
+
+    fs::write(spec_file, contents)?;
+    Ok(())
+}
+
+// requests read off the socket are dispatched to rocket in process, so nothing listens on tcp
+// and the socket's owner, group and mode are the only way in
+pub(crate) fn serve(rocket: Rocket, config: &SocketConfig) -> Result<(), Box<dyn Error>> {
+    let listener = bind(config)?;
+    let client = Arc::new(Client::untracked(rocket).map_err(|e| e.to_string())?);
+
+    if let Some(spec_file) = &config.spec_file {
+        write_spec_file(spec_file, format!("unix://{}", config.path.display()))?;
+    }
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let client = client.clone();
+
+                thread::spawn(move || {
+                    if let Err(e) = handle(stream, &client) {
+                        warn!("unix socket connection failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("unix socket accept failed: {}", e)
+        }
+    }
+
+    Ok(())
+}
+
+struct HttpRequest {
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    close: bool
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+// serves requests off one connection until the client closes it or asks for it to be closed
+fn handle(stream: UnixStream, client: &Client) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    loop {
+        match read_request(&mut reader) {
+            Ok(Some(request)) => {
+                let close = request.close;
+                writer.write_all(&dispatch(client, request))?;
+
+                if close {
+                    return Ok(());
+                }
+            }
+            Ok(None) => {
+                return Ok(())
+            }
+            Err(e) => {
+                writer.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
+                return Err(e)
+            }
+        }
+    }
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+
+    match reader.read_line(&mut line)? {
+        0 => Ok(None),
+        _ => Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()))
+    }
+}
+
+// None once the client has closed the connection between requests
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<HttpRequest>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None)
+    };
+
+    let mut parts = line.split_whitespace();
+    let (method, uri, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(uri), Some(version)) => (method.to_string(), uri.to_string(), version.to_string()),
+        _ => return Err(invalid("malformed request line"))
+    };
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+
+    loop {
+        match read_line(reader)? {
+            Some(line) if line.is_empty() => break,
+            Some(line) => {
+                match line.split_once(':') {
+                    Some((name, value)) => headers.push((name.trim().to_string(), value.trim().to_string())),
+                    None => return Err(invalid("malformed header"))
+                }
+            }
+            None => return Err(invalid("connection closed inside the headers"))
+        }
+    }
+
+    let header = |name: &str| headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.clone());
+
+    let body = match (header("Transfer-Encoding"), header("Content-Length")) {
+        (Some(encoding), _) if encoding.eq_ignore_ascii_case("chunked") => read_chunked(reader)?,
+        (_, Some(length)) => {
+            let mut body = vec![0; length.parse().map_err(|_| invalid("malformed content length"))?];
+            reader.read_exact(&mut body)?;
+            body
+        }
+        _ => Vec::new()
+    };
+    let close = match header("Connection") {
+        Some(connection) => connection.eq_ignore_ascii_case("close"),
+        None => version == "HTTP/1.0"
+    };
+
+    Ok(Some(HttpRequest {
+        method,
+        uri,
+        headers,
+        body,
+        close
+    }))
+}
+
+fn read_chunked(reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| invalid("connection closed inside a chunk"))?;
+        let size = usize::from_str_radix(line.split(';').next().unwrap_or_default().trim(), 16)
+            .map_err(|_| invalid("malformed chunk size"))?;
+
+        if size == 0 {
+            // trailers are read and dropped
+            while let Some(line) = read_line(reader)? {
+                if line.is_empty() {
+                    break;
+                }
+            }
+
+            return Ok(body);
+        }
+
+        let mut chunk = vec![0; size + 2];
+        reader.read_exact(&mut chunk)?;
+        body.extend_from_slice(&chunk[..size]);
+    }
+}
+
+fn dispatch(client: &Client, request: HttpRequest) -> Vec<u8> {
+    let method = match Method::from_str(&request.method) {
+        Ok(method) => method,
+        Err(_) => {
+            return b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n".to_vec()
+        }
+    };
+
+    let mut local = client.req(method, request.uri);
+    for (name, value) in request.headers {
+        local.add_header(Header::new(name, value));
+    }
+    local.set_body(request.body);
+
+    let mut response = local.dispatch();
+    let body = response.body_bytes().unwrap_or_default();
+    let status = response.status();
+
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status.code, status.reason);
+    for header in response.headers().iter() {
+        if !header.name().eq_ignore_ascii_case("Content-Length") && !header.name().eq_ignore_ascii_case("Transfer-Encoding") {
+            head.push_str(&format!("{}: {}\r\n", header.name(), header.value()));
+        }
+    }
+    head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    if request.close {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+#[cfg(test)]
+mod socket_tests {
+    use crate::socket::*;
+
+    fn socket_config(dir: &Path) -> SocketConfig {
+        SocketConfig {
+            path: dir.join("plugins").join("ipam.sock"),
+            owner: None,
+            group: None,
+            mode: Some(0o660),
+            spec_file: None
+        }
+    }
+
+    #[test]
+    fn bind_sets_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = socket_config(dir.path());
+        let _listener = bind(&config).unwrap();
+
+        let mode = fs::metadata(&config.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+    }
+
+    #[test]
+    fn bind_replaces_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = socket_config(dir.path());
+
+        drop(bind(&config).unwrap());
+        assert_eq!(config.path.exists(), true);
+        assert_eq!(bind(&config).is_ok(), true);
+    }
+
+    #[test]
+    fn bind_refuses_live_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = socket_config(dir.path());
+
+        let _live = bind(&config).unwrap();
+        assert_eq!(bind(&config).is_err(), true);
+    }
+
+    #[test]
+    fn bind_refuses_regular_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = socket_config(dir.path());
+
+        fs::create_dir_all(config.path.parent().unwrap()).unwrap();
+        fs::write(&config.path, "").unwrap();
+        assert_eq!(bind(&config).is_err(), true);
+    }
+
+    #[test]
+    fn spec_file_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = dir.path().join("ipam.spec");
+        let json = dir.path().join("ipam.json");
+
+        write_spec_file(&spec, "unix:///run/docker/plugins/ipam.sock".to_string()).unwrap();
+        write_spec_file(&json, "unix:///run/docker/plugins/ipam.sock".to_string()).unwrap();
+
+        assert_eq!(fs::read_to_string(&spec).unwrap(), "unix:///run/docker/plugins/ipam.sock");
+        assert_eq!(fs::read_to_string(&json).unwrap(), r#"{"Addr":"unix:///run/docker/plugins/ipam.sock","Name":"ipam"}"#);
+        assert_eq!(write_spec_file(&dir.path().join("ipam.txt"), "".to_string()).is_err(), true);
+    }
+}
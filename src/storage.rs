@@ -0,0 +1,570 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use unqlite::{Cursor, KV, UnQLite};
+use crate::error::*;
+
+pub trait Store: Send {
+    fn fetch(&self, key: &[u8]) -> IpamResult<Option<Vec<u8>>>;
+    fn store(&mut self, key: &[u8], value: &[u8]) -> IpamResult<()>;
+    fn delete(&mut self, key: &[u8]) -> IpamResult<()>;
+    fn entries(&self) -> IpamResult<Vec<(Vec<u8>, Vec<u8>)>>;
+    fn is_empty(&self) -> IpamResult<bool>;
+    fn begin(&mut self) -> IpamResult<()>;
+    fn commit(&mut self) -> IpamResult<()>;
+    fn rollback(&mut self) -> IpamResult<()>;
+
+    fn contains(&self, key: &[u8]) -> IpamResult<bool> {
+        Ok(self.fetch(key)?.is_some())
+    }
+}
+
+impl Store for UnQLite {
+    fn fetch(&self, key: &[u8]) -> IpamResult<Option<Vec<u8>>> {
+        match self.kv_contains(key) {
+            true => Ok(Some(self.kv_fetch(key)?)),
+            false => Ok(None)
+        }
+    }
+
+    fn store(&mut self, key: &[u8], value: &[u8]) -> IpamResult<()> {
+        Ok(self.kv_store(key, value)?)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> IpamResult<()> {
+        Ok(self.kv_delete(key)?)
+    }
+
+    fn entries(&self) -> IpamResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entry = self.first();
+        let mut ret: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+
+        loop {
+            if entry.is_none() {
+                break;
+            }
+            else {
+                let record = entry.expect("valid entry");
+                ret.push(record.key_value());
+                entry = record.next();
+            }
+        }
+
+        Ok(ret)
+    }
+
+    fn is_empty(&self) -> IpamResult<bool> {
+        Ok(self.first().is_none())
+    }
+
+    fn begin(&mut self) -> IpamResult<()> {
+        Ok(unqlite::Transaction::begin(self)?)
+    }
+
+    fn commit(&mut self) -> IpamResult<()> {
+        Ok(unqlite::Transaction::commit(self)?)
+    }
+
+    fn rollback(&mut self) -> IpamResult<()> {
+        Ok(unqlite::Transaction::rollback(self)?)
+    }
+}
+
+// an empty path opens a temporary store that goes away with the driver
+pub fn open_store(path: &str) -> IpamResult<Box<dyn Store>> {
+    match path.is_empty() {
+        true => Ok(Box::new(UnQLite::create_temp())),
+        false => Ok(Box::new(FileStore::open(path, Access::ReadWrite)?))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    ReadWrite,
+    ReadOnly
+}
+
+// an unqlite file guarded by two advisory locks next to it. A writer holds <path>.pid exclusively
+// for as long as it is open, with its PID inside, so a second writer fails fast instead of
+// interleaving with it. <path>.lock is held exclusively around every write and shared around every
+// read, which lets read-only tools run beside the daemon without seeing half a transaction. Such a
+// tool, a dump or a backup of the records, opens the files with Access::ReadOnly: it skips the .pid
+// claim, needs only read permission and gets an error from every write
+pub struct FileStore {
+    db: UnQLite,
+    path: String,
+    access: Access,
+    _owner: Option<File>,
+    lock: File,
+    in_transaction: bool
+}
+
+// flock(2) directly, since std's File locks need a newer toolchain than rocket 0.4 builds on
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    loop {
+        match unsafe { libc::flock(file.as_raw_fd(), operation) } {
+            0 => return Ok(()),
+            _ => {
+                let e = io::Error::last_os_error();
+
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl FileStore {
+    pub fn open(path: &str, access: Access) -> IpamResult<FileStore> {
+        let owner = match access {
+            Access::ReadWrite => Some(FileStore::claim(path)?),
+            Access::ReadOnly => None
+        };
+
+        // flock works on a read-only fd, so a reader needs no write permission on either file
+        let (lock, db) = match access {
+            Access::ReadWrite => {
+                let lock = OpenOptions::new().read(true).write(true).create(true).open(format!("{}.lock", path))?;
+                (lock, UnQLite::create(path))
+            }
+            Access::ReadOnly => {
+                if !Path::new(path).exists() {
+                    return Err(IpamError::Storage(format!("{} does not exist", path)));
+                }
+
+                let lock = OpenOptions::new().read(true).open(format!("{}.lock", path))?;
+                (lock, UnQLite::open_readonly(path))
+            }
+        };
+
+        Ok(FileStore {
+            db,
+            path: path.to_string(),
+            access,
+            _owner: owner,
+            lock,
+            in_transaction: false
+        })
+    }
+
+    fn claim(path: &str) -> IpamResult<File> {
+        let mut owner = OpenOptions::new().read(true).write(true).create(true).open(format!("{}.pid", path))?;
+
+        match flock(&owner, libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(_) => {
+                owner.set_len(0)?;
+                owner.write_all(std::process::id().to_string().as_bytes())?;
+                Ok(owner)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let mut pid = String::new();
+                owner.read_to_string(&mut pid)?;
+                Err(IpamError::DatabaseInUse(path.to_string(), pid.trim().parse().ok()))
+            }
+            Err(e) => Err(e.into())
+        }
+    }
+
+    fn writable(&self) -> IpamResult<()> {
+        match self.access {
+            Access::ReadWrite => Ok(()),
+            Access::ReadOnly => Err(IpamError::Storage(format!("{} is open read-only", self.path)))
+        }
+    }
+
+    // inside a transaction the exclusive lock is already held, and taking a shared lock on the
+    // same file would downgrade it
+    fn shared<T>(&self, f: impl FnOnce(&UnQLite) -> IpamResult<T>) -> IpamResult<T> {
+        if self.in_transaction {
+            return f(&self.db);
+        }
+
+        flock(&self.lock, libc::LOCK_SH)?;
+        let ret = f(&self.db);
+        flock(&self.lock, libc::LOCK_UN)?;
+        ret
+    }
+
+    // a write outside a transaction gets one of its own, otherwise readers would not see it
+    // until unqlite flushes its cache
+    fn exclusive<T>(&mut self, f: impl FnOnce(&mut UnQLite) -> IpamResult<T>) -> IpamResult<T> {
+        if self.in_transaction {
+            return f(&mut self.db);
+        }
+
+        self.begin()?;
+
+        match f(&mut self.db) {
+            Ok(ret) => {
+                self.commit()?;
+                Ok(ret)
+            }
+            Err(e) => {
+                self.rollback()?;
+                Err(e)
+            }
+        }
+    }
+
+    fn finish(&mut self, f: impl FnOnce(&mut UnQLite) -> IpamResult<()>) -> IpamResult<()> {
+        match self.in_transaction {
+            true => {
+                let ret = f(&mut self.db);
+                self.in_transaction = false;
+                flock(&self.lock, libc::LOCK_UN)?;
+                ret
+            }
+            false => Err(IpamError::Storage("no transaction is open".to_string()))
+        }
+    }
+}
+
+impl Store for FileStore {
+    fn fetch(&self, key: &[u8]) -> IpamResult<Option<Vec<u8>>> {
+        self.shared(|db| db.fetch(key))
+    }
+
+    fn store(&mut self, key: &[u8], value: &[u8]) -> IpamResult<()> {
+        self.exclusive(|db| db.store(key, value))
+    }
+
+    fn delete(&mut self, key: &[u8]) -> IpamResult<()> {
+        self.exclusive(|db| db.delete(key))
+    }
+
+    fn entries(&self) -> IpamResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.shared(|db| db.entries())
+    }
+
+    fn is_empty(&self) -> IpamResult<bool> {
+        self.shared(|db| db.is_empty())
+    }
+
+    // the write lock is taken for the whole transaction and given up when it ends either way
+    fn begin(&mut self) -> IpamResult<()> {
+        self.writable()?;
+
+        if self.in_transaction {
+            return Err(IpamError::Storage("a transaction is already open".to_string()));
+        }
+
+        flock(&self.lock, libc::LOCK_EX)?;
+
+        match Store::begin(&mut self.db) {
+            Ok(_) => {
+                self.in_transaction = true;
+                Ok(())
+            }
+            Err(e) => {
+                flock(&self.lock, libc::LOCK_UN)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn commit(&mut self) -> IpamResult<()> {
+        self.finish(|db| Store::commit(db))
+    }
+
+    fn rollback(&mut self) -> IpamResult<()> {
+        self.finish(|db| Store::rollback(db))
+    }
+}
+
+// keeps records in key order like unqlite's cursor; a transaction is a snapshot restored on rollback
+#[derive(Default)]
+pub struct MemoryStore {
+    records: BTreeMap<Vec<u8>, Vec<u8>>,
+    snapshot: Option<BTreeMap<Vec<u8>, Vec<u8>>>
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl Store for MemoryStore {
+    fn fetch(&self, key: &[u8]) -> IpamResult<Option<Vec<u8>>> {
+        Ok(self.records.get(key).cloned())
+    }
+
+    fn store(&mut self, key: &[u8], value: &[u8]) -> IpamResult<()> {
+        self.records.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> IpamResult<()> {
+        match self.records.remove(key) {
+            Some(_) => Ok(()),
+            None => Err(IpamError::Storage("no such key".to_string()))
+        }
+    }
+
+    fn entries(&self) -> IpamResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self.records.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    }
+
+    fn is_empty(&self) -> IpamResult<bool> {
+        Ok(self.records.is_empty())
+    }
+
+    fn begin(&mut self) -> IpamResult<()> {
+        match self.snapshot {
+            Some(_) => Err(IpamError::Storage("a transaction is already open".to_string())),
+            None => {
+                self.snapshot = Some(self.records.clone());
+                Ok(())
+            }
+        }
+    }
+
+    fn commit(&mut self) -> IpamResult<()> {
+        match self.snapshot.take() {
+            Some(_) => Ok(()),
+            None => Err(IpamError::Storage("commit without a transaction".to_string()))
+        }
+    }
+
+    fn rollback(&mut self) -> IpamResult<()> {
+        match self.snapshot.take() {
+            Some(snapshot) => {
+                self.records = snapshot;
+                Ok(())
+            }
+            None => Err(IpamError::Storage("rollback without a transaction".to_string()))
+        }
+    }
+}
+
+// rolls both stores back when dropped uncommitted; holding the stores mutably means no second
+// transaction can be opened on either of them while this one lives
+pub struct Transaction<'a> {
+    scope: &'a mut dyn Store,
+    schema: &'a mut dyn Store,
+    open: bool
+}
+
+impl<'a> Transaction<'a> {
+    pub fn begin(scope: &'a mut dyn Store, schema: &'a mut dyn Store) -> IpamResult<Transaction<'a>> {
+        scope.begin()?;
+
+        if let Err(e) = schema.begin() {
+            scope.rollback()?;
+            return Err(e);
+        }
+
+        Ok(Transaction {
+            scope,
+            schema,
+            open: true
+        })
+    }
+
+    pub fn stores(&mut self) -> (&mut dyn Store, &mut dyn Store) {
+        (&mut *self.scope, &mut *self.schema)
+    }
+
+    // the schema changes least often, so it commits first and a failure there leaves both untouched.
+    // The two files can't commit atomically though: should the scope commit fail after that, the
+    // schema keeps its half, a lock or the seeded records, which verify_databases checks the scope
+    // store against on the next start. A failed commit may already have ended the scope's
+    // transaction, so the rollback is best effort and the commit's error is what gets returned
+    pub fn commit(mut self) -> IpamResult<()> {
+        self.schema.commit()?;
+        self.open = false;
+
+        match self.scope.commit() {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let _ = self.scope.rollback();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if self.open {
+            let _ = self.scope.rollback();
+            let _ = self.schema.rollback();
+        }
+    }
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use crate::storage::*;
+
+    fn round_trip(store: &mut dyn Store) {
+        store.store(b"b", b"2").unwrap();
+        store.store(b"a", b"1").unwrap();
+
+        assert_eq!(store.fetch(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.fetch(b"c").unwrap(), None);
+        assert_eq!(store.entries().unwrap().len(), 2);
+
+        store.delete(b"b").unwrap();
+        assert_eq!(store.contains(b"b").unwrap(), false);
+        assert_eq!(store.is_empty().unwrap(), false);
+    }
+
+    #[test]
+    fn unqlite_round_trip() {
+        round_trip(&mut UnQLite::create_in_memory());
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        round_trip(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn empty_path_opens_a_temporary_store() {
+        let mut store = open_store("").unwrap();
+
+        assert_eq!(store.is_empty().unwrap(), true);
+        round_trip(store.as_mut());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scope.db").to_string_lossy().to_string();
+        open_store(&path).unwrap().store(b"a", b"1").unwrap();
+        assert_eq!(FileStore::open(&path, Access::ReadOnly).unwrap().fetch(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scope.db").to_string_lossy().to_string();
+        let mut store = FileStore::open(&path, Access::ReadWrite).unwrap();
+
+        round_trip(&mut store);
+        store.begin().unwrap();
+        store.store(b"c", b"3").unwrap();
+        store.rollback().unwrap();
+        assert_eq!(store.contains(b"c").unwrap(), false);
+    }
+
+    #[test]
+    fn second_writer_is_told_who_holds_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scope.db").to_string_lossy().to_string();
+        let mut writer = FileStore::open(&path, Access::ReadWrite).unwrap();
+        writer.store(b"a", b"1").unwrap();
+
+        assert_eq!(
+            FileStore::open(&path, Access::ReadWrite).err(),
+            Some(IpamError::DatabaseInUse(path.clone(), Some(std::process::id()))));
+
+        let mut reader = FileStore::open(&path, Access::ReadOnly).unwrap();
+        assert_eq!(reader.fetch(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(reader.store(b"b", b"2").is_err(), true);
+
+        drop(writer);
+        assert_eq!(FileStore::open(&path, Access::ReadWrite).is_ok(), true);
+    }
+
+    #[test]
+    fn readers_wait_for_open_transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scope.db").to_string_lossy().to_string();
+        let mut writer = FileStore::open(&path, Access::ReadWrite).unwrap();
+        writer.store(b"a", b"1").unwrap();
+        writer.begin().unwrap();
+        writer.store(b"a", b"2").unwrap();
+
+        let reader = std::thread::spawn({
+            let path = path.clone();
+            move || FileStore::open(&path, Access::ReadOnly).unwrap().fetch(b"a").unwrap()
+        });
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        writer.commit().unwrap();
+
+        assert_eq!(reader.join().unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn readers_need_an_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.db").to_string_lossy().to_string();
+
+        assert_eq!(FileStore::open(&path, Access::ReadOnly).is_err(), true);
+
+        // a reader does not create the lock file either, only a writer does
+        UnQLite::create(&path).kv_store(b"a", b"1").unwrap();
+        assert_eq!(FileStore::open(&path, Access::ReadOnly).is_err(), true);
+        assert_eq!(Path::new(&format!("{}.lock", path)).exists(), false);
+    }
+
+    #[test]
+    fn memory_rollback_restores_snapshot() {
+        let mut store = MemoryStore::new();
+        store.store(b"a", b"1").unwrap();
+
+        store.begin().unwrap();
+        store.store(b"a", b"2").unwrap();
+        store.store(b"b", b"3").unwrap();
+        store.rollback().unwrap();
+
+        assert_eq!(store.entries().unwrap(), vec![(b"a".to_vec(), b"1".to_vec())]);
+        assert_eq!(store.commit().is_err(), true);
+    }
+
+    #[test]
+    fn memory_rejects_nested_begin() {
+        let mut store = MemoryStore::new();
+
+        store.begin().unwrap();
+        assert_eq!(store.begin().err(), Some(IpamError::Storage("a transaction is already open".to_string())));
+    }
+
+    #[test]
+    fn dropped_transaction_rolls_back_both_stores() {
+        let mut scope = MemoryStore::new();
+        let mut schema = MemoryStore::new();
+
+        {
+            let mut tx = Transaction::begin(&mut scope, &mut schema).unwrap();
+            let (scope, schema) = tx.stores();
+            scope.store(b"a", b"1").unwrap();
+            schema.store(b"b", b"2").unwrap();
+        }
+
+        assert_eq!(scope.is_empty().unwrap(), true);
+        assert_eq!(schema.is_empty().unwrap(), true);
+
+        let mut tx = Transaction::begin(&mut scope, &mut schema).unwrap();
+        tx.stores().0.store(b"a", b"1").unwrap();
+        tx.commit().unwrap();
+
+        assert_eq!(scope.fetch(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(scope.begin().is_ok(), true);
+    }
+
+    #[test]
+    fn failed_begin_leaves_no_transaction_open() {
+        let mut scope = MemoryStore::new();
+        let mut schema = MemoryStore::new();
+        schema.begin().unwrap();
+
+        assert_eq!(Transaction::begin(&mut scope, &mut schema).is_err(), true);
+        assert_eq!(scope.begin().is_ok(), true);
+    }
+
+    #[test]
+    fn failed_scope_commit_reports_its_own_error() {
+        let mut scope = MemoryStore::new();
+        let mut schema = MemoryStore::new();
+
+        let mut tx = Transaction::begin(&mut scope, &mut schema).unwrap();
+        tx.stores().1.store(b"b", b"2").unwrap();
+        tx.stores().0.commit().unwrap();
+
+        assert_eq!(tx.commit().err(), Some(IpamError::Storage("commit without a transaction".to_string())));
+        assert_eq!(schema.fetch(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+}
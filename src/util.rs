@@ -1,17 +1,17 @@
-use std::{net::IpAddr, error::Error, str::FromStr};
+use std::{net::IpAddr, str::FromStr, time::SystemTime};
 use cidr::IpCidr;
-use unqlite::UnQLite;
-use crate::error::ScopeInitError;
+use crate::error::*;
 use crate::model::factory;
 use crate::scope::*;
 use crate::schema::*;
 use crate::model::*;
+use crate::storage::Store;
 
-pub fn string_to_ip_cidr(network: String, prefix_length: u8) -> Result<IpCidr, Box<dyn Error>> {
+pub fn string_to_ip_cidr(network: String, prefix_length: u8) -> IpamResult<IpCidr> {
     Ok(IpCidr::new(IpAddr::from_str(network.as_str())?, prefix_length)?)
 }
 
-pub fn string_to_u128_id(network: String) -> Result<u128, Box<dyn Error>> {
+pub fn string_to_u128_id(network: String) -> IpamResult<u128> {
     Ok(match IpAddr::from_str(network.as_str())? {
         IpAddr::V4(a) => {
             let v: u32 = a.into();
@@ -24,7 +24,38 @@ pub fn string_to_u128_id(network: String) -> Result<u128, Box<dyn Error>> {
     })
 }
 
-pub fn increment_address(network: IpAddr) -> Result<IpAddr, Box<dyn Error>> {
+pub fn id_to_ip_cidr(family: Family, id: u128, prefix_length: u8) -> IpamResult<IpCidr> {
+    match family {
+        Family::V6 => Ok(IpCidr::new(std::net::Ipv6Addr::from(id).into(), prefix_length)?),
+        Family::V4 => {
+            match u32::try_from(id) {
+                Ok(v) => Ok(IpCidr::new(std::net::Ipv4Addr::from(v).into(), prefix_length)?),
+                Err(_) => Err(IpamError::InvalidCidr(format!("{} is too large for an IPv4 id", id)))
+            }
+        }
+    }
+}
+
+// RFC 4193: fd00::/8 followed by a random 40 bit global id gives a /48 site prefix
+pub fn generate_ula_prefix() -> IpamResult<IpCidr> {
+    let global_id = rand::random::<u64>() & 0xff_ffff_ffff;
+    let prefix: u128 = (0xfd << 120) | ((global_id as u128) << 80);
+
+    Ok(IpCidr::new(std::net::Ipv6Addr::from(prefix).into(), 48)?)
+}
+
+pub fn overlaps(a: &IpCidr, b: &IpCidr) -> bool {
+    a.contains(&b.first_address()) || b.contains(&a.first_address())
+}
+
+pub fn string_to_ip_addr(address: String) -> IpamResult<IpAddr> {
+    match address.split('/').next() {
+        Some(a) => Ok(IpAddr::from_str(a)?),
+        None => Err(IpamError::InvalidCidr(address))
+    }
+}
+
+pub fn increment_address(network: IpAddr) -> IpamResult<IpAddr> {
     Ok(match IpAddr::from_str(&mut network.to_string())? {
         IpAddr::V4(a) => {
             let mut v: u32 = a.into();
@@ -38,21 +69,131 @@ pub fn increment_address(network: IpAddr) -> Result<IpAddr, Box<dyn Error>> {
         }
     })
 }
-pub fn create_initial_scopes(scope_db: &mut UnQLite, _schema_db: &mut UnQLite) -> Result<(), Box<dyn Error>> {
-    if Schema::retrieve_all()?
+
+// moves an address offset places along, within its own family
+pub fn offset_address(address: IpAddr, offset: u128) -> IpamResult<IpAddr> {
+    match address {
+        IpAddr::V4(a) => {
+            match u32::try_from(u128::from(u32::from(a)) + offset) {
+                Ok(v) => Ok(IpAddr::from(std::net::Ipv4Addr::from(v))),
+                Err(_) => Err(IpamError::InvalidCidr(format!("{} + {} is not an IPv4 address", a, offset)))
+            }
+        }
+        IpAddr::V6(a) => {
+            match u128::from(a).checked_add(offset) {
+                Some(v) => Ok(IpAddr::from(std::net::Ipv6Addr::from(v))),
+                None => Err(IpamError::InvalidCidr(format!("{} + {} is not an IPv6 address", a, offset)))
+            }
+        }
+    }
+}
+
+// FNV-1a, so every host and every build hashes a key to the same place
+pub fn stable_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3))
+}
+
+// records written before keys carried a family were keyed by the bare 16 byte id and the
+// family was guessed from its magnitude, so the same guess is used to rewrite them
+pub fn migrate_record_keys(db: &mut dyn Store) -> IpamResult<usize> {
+    let legacy: Vec<(Vec<u8>, Vec<u8>)> = db.entries()?
         .into_iter()
-        .map(|f| -> Result<Selection<Scope>, Box<dyn Error>> {
+        .filter(|(key, _)| key.len() == 16)
+        .collect();
+
+    for (key, value) in &legacy {
+        let mut id = [0u8; 16];
+        id.copy_from_slice(key);
+        let id = u128::from_be_bytes(id);
+        let family = match id > u32::MAX.into() {
+            true => Family::V6,
+            false => Family::V4
+        };
+
+        // serde_json::Value can't hold u128 ids, so the family field is spliced into the text
+        let record = String::from_utf8_lossy(value).to_string();
+        let record = match record.trim_start().strip_prefix('{') {
+            Some(fields) => format!("{{\"family\":{},{}", serde_json::to_string(&family)?, fields),
+            None => {
+                return Err(IpamError::Storage(format!("record {} is not an object", id)))
+            }
+        };
+
+        db.store(&family.key(id), record.as_bytes())?;
+        db.delete(key)?;
+    }
+
+    Ok(legacy.len())
+}
+
+pub fn create_initial_scopes(scope_db: &mut dyn Store, schema_db: &mut dyn Store) -> IpamResult<()> {
+    match Schema::records(schema_db)?
+        .into_iter()
+        .map(|f| -> IpamResult<Selection<Scope>> {
             Scope::new_from_selection(f)
         })
-        .map(|f| -> Result<(), Box<dyn Error>> {
+        .map(|f| -> IpamResult<()> {
             Scope::save(&mut f?, scope_db)
         })
-        .any(|f| -> bool {
-            f.is_err()
-        }) {
-            Err(ScopeInitError.into())
+        .find_map(|f| f.err()) {
+            Some(e) => Err(IpamError::ScopeInit(e.to_string())),
+            None => Ok(())
+        }
+}
+// a lock is kept on the schema node and on the scope mirroring it, whichever of the two exist
+pub fn set_lock(pool: &IpCidr, lock: Option<Lock>, scope_db: &mut dyn Store, schema_db: &mut dyn Store) -> IpamResult<bool> {
+    let mut found = false;
+    let mut changed = false;
+
+    if let Some(mut node) = Schema::node(pool, schema_db)? {
+        changed |= match &lock {
+            Some(lock) => node.lock(lock.clone())?,
+            None => node.unlock()?
+        };
+        Schema::save(&mut node, schema_db)?;
+        found = true;
+    }
+
+    match Scope::select(&format!("{:#}", pool), scope_db) {
+        Ok(mut selection) => {
+            changed |= match &lock {
+                Some(lock) => selection.lock(lock.clone())?,
+                None => selection.unlock()?
+            };
+            selection.actual.modified = SystemTime::now();
+            Scope::compare_and_save(&mut selection, scope_db)?;
+            found = true;
         }
-        else {
-            Ok(())
+        Err(IpamError::NoSuchPool(_)) => (),
+        Err(e) => {
+            return Err(e)
         }
-}
\ No newline at end of file
+    }
+
+    match found {
+        true => Ok(changed),
+        false => Err(IpamError::NoSuchPool(format!("{:#}", pool)))
+    }
+}
+
+pub fn locks(scope_db: &dyn Store, schema_db: &dyn Store) -> IpamResult<Vec<(IpCidr, Option<Lock>)>> {
+    let mut ret: Vec<(IpCidr, Option<Lock>)> = Vec::new();
+
+    for record in Schema::records(schema_db)? {
+        for description in record.actual.descriptions.iter().filter(|d| d.locked) {
+            ret.push((
+                id_to_ip_cidr(record.actual.family, record.actual.pool, description.prefix_length)?,
+                description.lock.clone()));
+        }
+    }
+
+    for (pool, lock) in Scope::locked_pools(&Scope::records(scope_db)?)? {
+        match ret.iter_mut().find(|(p, _)| *p == pool) {
+            Some(existing) if existing.1.is_none() => existing.1 = lock,
+            Some(_) => (),
+            None => ret.push((pool, lock))
+        }
+    }
+
+    Ok(ret)
+}